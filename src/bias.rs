@@ -0,0 +1,91 @@
+use rand::Rng;
+
+use crate::{GeneratorData, texture::Texture, vector3float::Vector3Float};
+
+pub const BIAS_NAMES: [&str; 2] = ["polynomial", "smooth"];
+
+/// Largest order of the polynomial bias field.
+pub const MAX_ORDER: u32 = 5;
+/// Order of the polynomial when it is not given.
+pub const DEFAULT_ORDER: u32 = 3;
+
+/// Legendre polynomials of order 0 to the given order at x, by their recurrence.
+fn legendre(x: f32, order: usize) -> Vec<f32> {
+    let mut values = vec![1.0, x];
+    for n in 1..order {
+        let next = ((2 * n + 1) as f32 * x * values[n] - n as f32 * values[n - 1]) / (n + 1) as f32;
+        values.push(next);
+    }
+    values.truncate(order + 1);
+    return values;
+}
+
+/// Random multiplicative bias field, the exponential of a smooth function so it stays positive and varies around 1.
+/// The polynomial field sums products of Legendre polynomials along the axes up to the order, with coefficients
+/// uniform in [-strength, strength] and without the constant term. The smooth field is Perlin noise with the
+/// correlation length as its scale, times the strength.
+pub fn generate_bias_field(gen_data: &GeneratorData) -> Vec<f32> {
+    let mut rand = rand::thread_rng();
+    let p = &gen_data.bias_parameters;
+    let d = &gen_data.pixel_dimensions;
+    let spacing = gen_data.spacing();
+    let e = &gen_data.extent;
+    let strength = p[0];
+    let mut field = Vec::with_capacity(d.x * d.y * d.z);
+
+    if gen_data.bias.as_deref() == Some("smooth") {
+        let texture = Texture {
+            kind: String::from("perlin"),
+            scale: p[1],
+            octaves: 1,
+            amplitude: strength
+        };
+        let seed = rand.gen::<u32>();
+        for k in 0..d.z {
+            for j in 0..d.y {
+                for i in 0..d.x {
+                    let q = Vector3Float::new(spacing.x * (i as f32 + 0.5), spacing.y * (j as f32 + 0.5), spacing.z * (k as f32 + 0.5));
+                    field.push(texture.value(&q, seed).exp());
+                }
+            }
+        }
+        return field;
+    }
+
+    let order = p[1] as usize;
+    let mut coefficients = Vec::new();
+    for a in 0..=order {
+        for b in 0..=order - a {
+            for c in 0..=order - a - b {
+                if a + b + c > 0 {
+                    coefficients.push((a, b, c, strength * (2.0 * rand.gen::<f32>() - 1.0)));
+                }
+            }
+        }
+    }
+
+    // Polynomials are evaluated on [-1, 1] along every axis
+    let axis = |n: usize, h: f32, size: f32| -> Vec<Vec<f32>> {
+        return (0..n).map(|i| legendre(2.0 * h * (i as f32 + 0.5) / size - 1.0, order)).collect();
+    };
+    let (lx, ly, lz) = (axis(d.x, spacing.x, e.x), axis(d.y, spacing.y, e.y), axis(d.z, spacing.z, e.z));
+    for pz in lz.iter() {
+        for py in ly.iter() {
+            for px in lx.iter() {
+                let exponent: f32 = coefficients.iter().map(|(a, b, c, w)| w * px[*a] * py[*b] * pz[*c]).sum();
+                field.push(exponent.exp());
+            }
+        }
+    }
+    return field;
+}
+
+/// Bias field and parameters for the execution data.
+pub fn describe(gen_data: &GeneratorData) -> String {
+    let p = &gen_data.bias_parameters;
+    match gen_data.bias.as_deref() {
+        Some("polynomial") => format!("polynomial, strength {}, order {}", p[0], p[1]),
+        Some(_) => format!("smooth, strength {}, correlation length {}", p[0], p[1]),
+        None => String::from("none")
+    }
+}
@@ -0,0 +1,128 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use crate::{GeneratorData, csg::{CsgNode, Primitive, Transform}, instance::Instance, vector3float::Vector3Float};
+
+pub const CONTAINER_NAMES: [&str; 3] = ["cylinder", "tube", "box"];
+
+/// Half of the outer width of the container as a fraction of the smaller of the x and y extents,
+/// it is centered in x and y and spans the whole height.
+const OUTER_EXTENT: f32 = 0.45;
+/// Step of the central differences used for the distance gradient.
+const GRADIENT_STEP: f32 = 0.0001;
+
+/// Sample holder around the instances. Cylinders and boxes have a bottom, tubes are open on both ends.
+/// The inside of the container is filled with the background, the outside with air.
+pub struct Container {
+    wall: CsgNode,
+    interior: CsgNode,
+    density: u32,
+    extent: Vector3Float,
+    interior_volume: f32
+}
+
+impl Container {
+    pub fn new(gen_data: &GeneratorData) -> Option<Self> {
+        let name = match &gen_data.container {
+            Some(c) => c.as_str(),
+            None => {
+                return None;
+            }
+        };
+        let e = &gen_data.extent;
+        let outer_extent = OUTER_EXTENT * e.x.min(e.y);
+        let half_height = e.z / 2.0;
+        let t = gen_data.container_wall.clamp(0.0, outer_extent);
+        let center = Vector3Float::new(e.x / 2.0, e.y / 2.0, half_height);
+        // The interior of a tube goes through the whole volume, other containers keep a bottom.
+        // It ends at the top of the volume, so it only covers space spheres can occupy
+        let bottom = if name == "tube" { 0.0 } else { t };
+        let interior_half_height = (e.z - bottom) / 2.0;
+        let interior_center = Vector3Float::new(e.x / 2.0, e.y / 2.0, bottom + interior_half_height);
+
+        let (outer, inner) = match name {
+            "box" => (
+                Primitive::Cuboid { half_extents: Vector3Float::new(outer_extent, outer_extent, half_height) },
+                Primitive::Cuboid { half_extents: Vector3Float::new(outer_extent - t, outer_extent - t, interior_half_height) }
+            ),
+            _ => (
+                Primitive::Cylinder { radius: outer_extent, half_height },
+                Primitive::Cylinder { radius: outer_extent - t, half_height: interior_half_height }
+            )
+        };
+
+        let interior_volume = match &inner {
+            Primitive::Cuboid { half_extents } => 4.0 * half_extents.x * half_extents.y,
+            _ => PI * (outer_extent - t) * (outer_extent - t)
+        } * 2.0 * interior_half_height;
+        let interior = CsgNode::Primitive(inner, Transform::new(interior_center));
+        let wall = CsgNode::Difference(vec![CsgNode::Primitive(outer, Transform::new(center)), interior.clone()]);
+        return Some(Self {
+            wall,
+            interior,
+            density: gen_data.container_density,
+            extent: e.clone(),
+            interior_volume
+        });
+    }
+
+    /// The container wall, labelled as its own instance.
+    pub fn wall(&self, id: u32) -> Instance {
+        return Instance::new(id, self.density, self.wall.clone()).with_class("container");
+    }
+
+    /// The inside of the container, only its shape is used by the renderers.
+    pub fn interior(&self) -> Instance {
+        return Instance::new(0, 0, self.interior.clone()).with_class("interior");
+    }
+
+    /// Volume of the inside of the container within the volume, in cubic scene units.
+    pub fn interior_volume(&self) -> f32 {
+        return self.interior_volume;
+    }
+
+    /// Whether a sphere at the point is fully inside the container.
+    pub fn fits(&self, p: &Vector3Float, r: f32) -> bool {
+        return self.interior.signed_distance(p) <= -r;
+    }
+
+    /// Moves a sphere that sticks out of the container back inside, along the distance gradient.
+    pub fn constrain(&self, p: &mut [f32; 3], r: f32) {
+        let v = Vector3Float::new(p[0], p[1], p[2]);
+        let d = self.interior.signed_distance(&v);
+        if d <= -r {
+            return;
+        }
+
+        let mut gradient = [0.0; 3];
+        for axis in 0..3 {
+            let mut a = [v.x, v.y, v.z];
+            let mut b = [v.x, v.y, v.z];
+            a[axis] += GRADIENT_STEP;
+            b[axis] -= GRADIENT_STEP;
+            let da = self.interior.signed_distance(&Vector3Float::new(a[0], a[1], a[2]));
+            let db = self.interior.signed_distance(&Vector3Float::new(b[0], b[1], b[2]));
+            gradient[axis] = (da - db) / (2.0 * GRADIENT_STEP);
+        }
+        let length = (gradient[0]*gradient[0] + gradient[1]*gradient[1] + gradient[2]*gradient[2]).sqrt();
+        if length == 0.0 {
+            return;
+        }
+        for axis in 0..3 {
+            p[axis] -= gradient[axis] / length * (d + r);
+        }
+    }
+
+    /// Random position of a sphere inside the container, or None if none was found in the given attempts.
+    pub fn random_position(&self, r: f32, attempts: u32) -> Option<[f32; 3]> {
+        let mut rand = rand::thread_rng();
+        for _ in 0..attempts {
+            let p = Vector3Float::new(rand.gen::<f32>() * self.extent.x, rand.gen::<f32>() * self.extent.y, rand.gen::<f32>() * self.extent.z);
+            if self.fits(&p, r) {
+                return Some([p.x, p.y, p.z]);
+            }
+        }
+        return None;
+    }
+}
@@ -1,74 +1,163 @@
-use std::f32::consts::PI;
+use crate::{GeneratorData, VolumeTextures, instance::{Instance, thin_label_threshold}, vector3float::Vector3Float, texture, overlap::{self, OVERLAP_FIRST, OVERLAP_MAX_DENSITY, OVERLAP_ADD, OVERLAP_AVERAGE}};
 
-use crate::{GeneratorData, sphere::Sphere, vector3float::Vector3Float};
+/// Label and density of the solid instances at the point, and how many instances contain it.
+fn resolve_solid(p: &Vector3Float, instances: &Vec<Instance>, mode: i32, count_overlaps: bool, max_density: f32) -> (u32, f32, u32) {
+    let mut material = 0;
+    let mut density = 0.0;
+    let mut solid_count = 0;
+    let mut density_sum = 0.0;
+    for instance in instances.iter().filter(|i| !i.is_thin()) {
+        if !instance.contains(p) {
+            continue;
+        }
 
-fn normal_dist(mu: f32, sig: f32) -> f32 {
-    let u1 = rand::random::<f32>();
-    let u2 = rand::random::<f32>();
+        // The first instance in the list labels the voxel, unless the densest one wins
+        let instance_density = instance.density_at(p, max_density);
+        if material == 0 || (mode == OVERLAP_MAX_DENSITY && instance_density > density) {
+            material = instance.id();
+            density = instance_density;
+        }
+        solid_count += 1;
+        density_sum += instance_density;
+        if mode == OVERLAP_FIRST && !count_overlaps {
+            break;
+        }
+    }
+    if mode == OVERLAP_ADD {
+        density = density_sum.min(max_density);
+    } else if mode == OVERLAP_AVERAGE && solid_count > 0 {
+        density = density_sum / solid_count as f32;
+    }
+    return (material, density, solid_count);
+}
 
-    let r = (-2.0 * u1.ln()).sqrt();
-    let cos = (2.0 * PI * u2).cos();
-    return mu + sig.sqrt() * (r * cos);
+/// Density with the instance texture for instance ids, or the background texture for id 0.
+fn textured_density(gen_data: &GeneratorData, id: u32, density: f32, p: &Vector3Float, seed: u32) -> f32 {
+    let texture = if id != 0 { gen_data.texture.as_ref() } else { gen_data.background_texture.as_ref() };
+    match texture {
+        Some(t) => (density + t.value(p, texture::instance_seed(seed, id))).clamp(0.0, gen_data.max_density()),
+        None => density
+    }
 }
 
-fn get_density_and_material(p: Vector3Float, spheres: &Vec<Sphere>, gen_data: &GeneratorData) -> (u32, i32) {
-    let mut current_rad = f32::MAX;
-    let mut material = i32::MAX;
-    let mut density = 0;
-    for sphere in spheres {
-        if sphere.is_point_in_sphere(&p) {
-            if sphere.radius() <= current_rad && sphere.id() < material {
-                current_rad = sphere.radius();
-                material = sphere.id();
-                density = sphere.density();
-            }
+/// The background fills the inside of the container, and air the outside.
+fn is_air(p: &Vector3Float, interior: Option<&Instance>) -> bool {
+    match interior {
+        Some(interior) => !interior.contains(p),
+        None => false
+    }
+}
+
+/// Clean density, label, number of instances covering the voxel at the point and the fraction of the voxel they fill.
+fn get_density_and_material(p: Vector3Float, instances: &Vec<Instance>, interior: Option<&Instance>, gen_data: &GeneratorData, seed: u32) -> (f32, u32, u32, f32) {
+    let voxel_size = gen_data.voxel_size();
+    let mode = overlap::overlap_mode(gen_data.overlap.as_ref());
+
+    // Thin instances are mixed with whatever is below them by their coverage
+    let mut thin: Option<&Instance> = None;
+    let mut thin_coverage = 0.0;
+    let mut overlap_count = 0;
+    for instance in instances.iter().filter(|i| i.is_thin()) {
+        let coverage = instance.coverage(&p, voxel_size);
+        if coverage >= thin_label_threshold(instance.thickness(), voxel_size) {
+            overlap_count += 1;
+        }
+        if coverage > thin_coverage {
+            thin = Some(instance);
+            thin_coverage = coverage;
         }
     }
 
-    if material == i32::MAX {
-        material = -1;
-        let mu = gen_data.empty_space as f32 / 2.0;
-        density = normal_dist(mu, mu) as u32;
+    // The label is taken at the voxel center
+    let (mut material, density, solid_count) = resolve_solid(&p, instances, mode, gen_data.overlap_map, gen_data.max_density());
+    overlap_count += solid_count;
+    let air = material == 0 && is_air(&p, interior);
+
+    let mut mu = if air {
+        0.0
+    } else if material == 0 {
+        textured_density(gen_data, 0, gen_data.empty_space as f32 / 2.0, &p, seed)
     } else {
-        let mu = density as f32;
-        let phi = gen_data.noise_span as f32;
-        density = normal_dist(mu, phi) as u32;
+        textured_density(gen_data, material, density, &p, seed)
+    };
+    let mut filled = if material != 0 { 1.0 } else { 0.0 };
+
+    // With supersampling, the density is the mean over a regular grid of points in the voxel
+    let n = gen_data.supersampling;
+    if n > 1 {
+        let spacing = gen_data.spacing();
+        let mut mu_sum = 0.0;
+        let mut filled_count = 0;
+        for sz in 0..n {
+            for sy in 0..n {
+                for sx in 0..n {
+                    let offset = |s: u32| (s as f32 + 0.5) / n as f32 - 0.5;
+                    let q = Vector3Float::new(p.x + spacing.x * offset(sx), p.y + spacing.y * offset(sy), p.z + spacing.z * offset(sz));
+                    let (id, density, _) = resolve_solid(&q, instances, mode, false, gen_data.max_density());
+                    if id != 0 {
+                        mu_sum += textured_density(gen_data, id, density, &q, seed);
+                        filled_count += 1;
+                    } else if !is_air(&q, interior) {
+                        mu_sum += textured_density(gen_data, 0, gen_data.empty_space as f32 / 2.0, &q, seed);
+                    }
+                }
+            }
+        }
+        let samples = (n * n * n) as f32;
+        mu = mu_sum / samples;
+        filled = filled_count as f32 / samples;
     }
 
-    return (density, material);
+    if let Some(thin) = thin {
+        mu = thin_coverage * thin.density() as f32 + (1.0 - thin_coverage) * mu;
+        filled = thin_coverage + (1.0 - thin_coverage) * filled;
+        if thin_coverage >= thin_label_threshold(thin.thickness(), voxel_size) {
+            material = thin.id();
+        }
+    }
+
+    return (mu, material, overlap_count, filled);
 }
 
 fn get_point(gen_data: &GeneratorData, i: usize, j: usize, k: usize) -> Vector3Float {
-    let size_x = gen_data.pixel_dimensions.x as f32;
-    let size_y = gen_data.pixel_dimensions.y as f32;
-    let size_z = gen_data.pixel_dimensions.z as f32;
+    let spacing = gen_data.spacing();
 
-    let x = (1.0 / size_x) * (i as f32 + 0.5);
-    let y = (1.0 / size_y) * (j as f32 + 0.5);
-    let z = (1.0 / size_z) * (k as f32 + 0.5);
+    let x = spacing.x * (i as f32 + 0.5);
+    let y = spacing.y * (j as f32 + 0.5);
+    let z = spacing.z * (k as f32 + 0.5);
 
     return Vector3Float::new(x, y, z);
 }
 
-pub fn generate_volume_textures(gen_data: &GeneratorData, spheres: &Vec<Sphere>) -> (Vec<i32>, Vec<u32>) {
+pub fn generate_volume_textures(gen_data: &GeneratorData, instances: &Vec<Instance>, interior: Option<&Instance>) -> VolumeTextures {
     let size_x = gen_data.pixel_dimensions.x;
     let size_y = gen_data.pixel_dimensions.y;
     let size_z = gen_data.pixel_dimensions.z;
-    
+
     let mut material_texture = Vec::with_capacity(size_x * size_y * size_z);
     let mut density_texture = Vec::with_capacity(size_x * size_y * size_z);
+    let mut overlap_texture = Vec::with_capacity(size_x * size_y * size_z);
+    let mut occupancy_texture = Vec::with_capacity(size_x * size_y * size_z);
+    let seed = rand::random::<u32>();
 
-    for i in 0..size_x {
+    // Same memory layout as the OpenCL kernel, x changes fastest
+    for k in 0..size_z {
         for j in 0..size_y {
-            for k in 0..size_z {
+            for i in 0..size_x {
                 let p = get_point(gen_data, i, j, k);
-                let (density, material) = get_density_and_material(p, spheres, gen_data);
-                //println!("{} {}", density, material);
+                let (density, material, overlaps, occupancy) = get_density_and_material(p, instances, interior, gen_data, seed);
                 material_texture.push(material);
                 density_texture.push(density);
+                overlap_texture.push(overlaps);
+                occupancy_texture.push(occupancy);
             }
         }
     }
     println!("Generated material texture and density texture");
-    return (material_texture, density_texture);
-}
\ No newline at end of file
+    return VolumeTextures {
+        materials: material_texture,
+        densities: density_texture,
+        overlaps: overlap_texture,
+        occupancy: occupancy_texture
+    };
+}
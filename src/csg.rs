@@ -0,0 +1,364 @@
+use crate::vector3float::Vector3Float;
+
+pub type BoundingBox = ((f32, f32), (f32, f32), (f32, f32));
+
+/// Number of floats used by one encoded node: operation, origin (3),
+/// world-to-local rotation (9) and primitive parameters (3).
+pub const NODE_STRIDE: usize = 16;
+
+/// Size of the evaluation stack in kernel.cl, limits the nesting of CSG trees.
+pub const MAX_STACK_DEPTH: usize = 16;
+
+const OP_SPHERE: f32 = 0.0;
+const OP_ELLIPSOID: f32 = 1.0;
+const OP_CYLINDER: f32 = 2.0;
+const OP_CUBOID: f32 = 3.0;
+const OP_UNION: f32 = 10.0;
+const OP_INTERSECTION: f32 = 11.0;
+const OP_DIFFERENCE: f32 = 12.0;
+const OP_SURFACE: f32 = 13.0;
+
+#[derive(Clone)]
+pub enum Primitive {
+    Sphere { radius: f32 },
+    Ellipsoid { radii: Vector3Float },
+    /// Cylinder along the local z axis
+    Cylinder { radius: f32, half_height: f32 },
+    Cuboid { half_extents: Vector3Float }
+}
+
+/// Position and orientation of a primitive. The rotation is stored as
+/// a world-to-local matrix, so a point is first moved to the origin and then rotated.
+#[derive(Clone)]
+pub struct Transform {
+    pub origin: Vector3Float,
+    pub rotation: [[f32; 3]; 3]
+}
+
+#[derive(Clone)]
+pub enum CsgNode {
+    Primitive(Primitive, Transform),
+    Union(Vec<CsgNode>),
+    Intersection(Vec<CsgNode>),
+    /// The first node with all the following nodes removed from it
+    Difference(Vec<CsgNode>),
+    /// Unsigned distance to the boundary of the node, used for thin sheets
+    Surface(Box<CsgNode>)
+}
+
+impl Transform {
+    pub fn new(origin: Vector3Float) -> Self {
+        return Self {
+            origin,
+            rotation: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]
+        }
+    }
+
+    pub fn with_rotation(origin: Vector3Float, rotation: [[f32; 3]; 3]) -> Self {
+        return Self {
+            origin,
+            rotation
+        }
+    }
+
+    /// Creates a transform rotated around x, then y, then z axis by the given angles in degrees.
+    pub fn with_euler_angles(origin: Vector3Float, angles: &Vector3Float) -> Self {
+        let (sx, cx) = angles.x.to_radians().sin_cos();
+        let (sy, cy) = angles.y.to_radians().sin_cos();
+        let (sz, cz) = angles.z.to_radians().sin_cos();
+
+        // Local-to-world rotation Rz * Ry * Rx
+        let m = [
+            [cz*cy, cz*sy*sx - sz*cx, cz*sy*cx + sz*sx],
+            [sz*cy, sz*sy*sx + cz*cx, sz*sy*cx - cz*sx],
+            [-sy, cy*sx, cy*cx]
+        ];
+
+        return Self {
+            origin,
+            rotation: transpose(&m)
+        }
+    }
+
+    /// Inverse of `with_euler_angles`, returns the angles in degrees.
+    pub fn euler_angles(&self) -> Vector3Float {
+        // Rows of the world-to-local matrix are columns of local-to-world
+        let r = &self.rotation;
+        let y = (-r[0][2]).clamp(-1.0, 1.0).asin();
+        let x = r[1][2].atan2(r[2][2]);
+        let z = r[0][1].atan2(r[0][0]);
+        return Vector3Float::new(x.to_degrees(), y.to_degrees(), z.to_degrees());
+    }
+
+    fn is_rotated(&self) -> bool {
+        let r = &self.rotation;
+        return r[0][1] != 0.0 || r[0][2] != 0.0 || r[1][0] != 0.0 || r[1][2] != 0.0 || r[2][0] != 0.0 || r[2][1] != 0.0;
+    }
+
+    pub fn to_local(&self, p: &Vector3Float) -> Vector3Float {
+        let d = Vector3Float::sub(p, &self.origin);
+        let r = &self.rotation;
+        return Vector3Float::new(
+            r[0][0]*d.x + r[0][1]*d.y + r[0][2]*d.z,
+            r[1][0]*d.x + r[1][1]*d.y + r[1][2]*d.z,
+            r[2][0]*d.x + r[2][1]*d.y + r[2][2]*d.z
+        );
+    }
+
+    pub fn to_world(&self, q: &Vector3Float) -> Vector3Float {
+        let r = &self.rotation;
+        return Vector3Float::new(
+            self.origin.x + r[0][0]*q.x + r[1][0]*q.y + r[2][0]*q.z,
+            self.origin.y + r[0][1]*q.x + r[1][1]*q.y + r[2][1]*q.z,
+            self.origin.z + r[0][2]*q.x + r[1][2]*q.y + r[2][2]*q.z
+        );
+    }
+
+    /// Half extents of the world axis-aligned box around a local box with the given half extents.
+    fn world_extents(&self, h: &Vector3Float) -> Vector3Float {
+        let r = &self.rotation;
+        // Columns of the world-to-local matrix are rows of local-to-world
+        let ex = r[0][0].abs()*h.x + r[1][0].abs()*h.y + r[2][0].abs()*h.z;
+        let ey = r[0][1].abs()*h.x + r[1][1].abs()*h.y + r[2][1].abs()*h.z;
+        let ez = r[0][2].abs()*h.x + r[1][2].abs()*h.y + r[2][2].abs()*h.z;
+        return Vector3Float::new(ex, ey, ez);
+    }
+}
+
+fn transpose(m: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    return [
+        [m[0][0], m[1][0], m[2][0]],
+        [m[0][1], m[1][1], m[2][1]],
+        [m[0][2], m[1][2], m[2][2]]
+    ];
+}
+
+impl Primitive {
+    /// Signed distance from a point in local coordinates, negative inside.
+    /// Ellipsoids use a bounded approximation, which is exact on the surface.
+    pub fn signed_distance(&self, q: &Vector3Float) -> f32 {
+        match self {
+            Primitive::Sphere { radius } => {
+                return q.length() - radius;
+            },
+            Primitive::Ellipsoid { radii } => {
+                let k0 = Vector3Float::new(q.x / radii.x, q.y / radii.y, q.z / radii.z).length();
+                let k1 = Vector3Float::new(q.x / (radii.x*radii.x), q.y / (radii.y*radii.y), q.z / (radii.z*radii.z)).length();
+                if k1 == 0.0 {
+                    return -radii.x.min(radii.y).min(radii.z);
+                }
+                return k0 * (k0 - 1.0) / k1;
+            },
+            Primitive::Cylinder { radius, half_height } => {
+                let dx = (q.x*q.x + q.y*q.y).sqrt() - radius;
+                let dy = q.z.abs() - half_height;
+                let outside = (dx.max(0.0).powi(2) + dy.max(0.0).powi(2)).sqrt();
+                return dx.max(dy).min(0.0) + outside;
+            },
+            Primitive::Cuboid { half_extents } => {
+                let dx = q.x.abs() - half_extents.x;
+                let dy = q.y.abs() - half_extents.y;
+                let dz = q.z.abs() - half_extents.z;
+                let outside = Vector3Float::new(dx.max(0.0), dy.max(0.0), dz.max(0.0)).length();
+                return dx.max(dy).max(dz).min(0.0) + outside;
+            }
+        }
+    }
+
+    /// Describes the primitive in the scene file syntax.
+    pub fn to_scene_string(&self, transform: &Transform) -> String {
+        let o = &transform.origin;
+        let mut text = match self {
+            Primitive::Sphere { radius } => {
+                format!("(sphere {} {} {} {}", o.x, o.y, o.z, radius)
+            },
+            Primitive::Ellipsoid { radii } => {
+                format!("(ellipsoid {} {} {} {} {} {}", o.x, o.y, o.z, radii.x, radii.y, radii.z)
+            },
+            Primitive::Cylinder { radius, half_height } => {
+                format!("(cylinder {} {} {} {} {}", o.x, o.y, o.z, radius, half_height * 2.0)
+            },
+            Primitive::Cuboid { half_extents: h } => {
+                format!("(box {} {} {} {} {} {}", o.x, o.y, o.z, h.x * 2.0, h.y * 2.0, h.z * 2.0)
+            }
+        };
+        if transform.is_rotated() {
+            let a = transform.euler_angles();
+            text.push_str(&format!(" {} {} {}", a.x, a.y, a.z));
+        }
+        text.push(')');
+        return text;
+    }
+
+    fn local_extents(&self) -> Vector3Float {
+        match self {
+            Primitive::Sphere { radius } => Vector3Float::new(*radius, *radius, *radius),
+            Primitive::Ellipsoid { radii } => radii.clone(),
+            Primitive::Cylinder { radius, half_height } => Vector3Float::new(*radius, *radius, *half_height),
+            Primitive::Cuboid { half_extents } => half_extents.clone()
+        }
+    }
+
+    fn encode(&self, transform: &Transform, nodes: &mut Vec<f32>) {
+        let (op, params) = match self {
+            Primitive::Sphere { radius } => (OP_SPHERE, [*radius, 0.0, 0.0]),
+            Primitive::Ellipsoid { radii } => (OP_ELLIPSOID, [radii.x, radii.y, radii.z]),
+            Primitive::Cylinder { radius, half_height } => (OP_CYLINDER, [*radius, *half_height, 0.0]),
+            Primitive::Cuboid { half_extents } => (OP_CUBOID, [half_extents.x, half_extents.y, half_extents.z])
+        };
+
+        nodes.push(op);
+        nodes.push(transform.origin.x);
+        nodes.push(transform.origin.y);
+        nodes.push(transform.origin.z);
+        for row in &transform.rotation {
+            nodes.extend_from_slice(row);
+        }
+        nodes.extend_from_slice(&params);
+    }
+}
+
+impl CsgNode {
+    pub fn signed_distance(&self, p: &Vector3Float) -> f32 {
+        match self {
+            CsgNode::Primitive(primitive, transform) => {
+                return primitive.signed_distance(&transform.to_local(p));
+            },
+            CsgNode::Union(children) => {
+                return children.iter().map(|c| c.signed_distance(p)).fold(f32::MAX, f32::min);
+            },
+            CsgNode::Intersection(children) => {
+                return children.iter().map(|c| c.signed_distance(p)).fold(f32::MIN, f32::max);
+            },
+            CsgNode::Difference(children) => {
+                let mut d = children[0].signed_distance(p);
+                for child in &children[1..] {
+                    d = d.max(-child.signed_distance(p));
+                }
+                return d;
+            },
+            CsgNode::Surface(child) => {
+                return child.signed_distance(p).abs();
+            }
+        }
+    }
+
+    pub fn to_scene_string(&self) -> String {
+        let (name, children) = match self {
+            CsgNode::Primitive(primitive, transform) => {
+                return primitive.to_scene_string(transform);
+            },
+            CsgNode::Union(children) => ("union", children),
+            CsgNode::Intersection(children) => ("intersection", children),
+            CsgNode::Difference(children) => ("difference", children),
+            CsgNode::Surface(child) => {
+                return format!("(surface {})", child.to_scene_string());
+            }
+        };
+
+        let mut text = format!("({}", name);
+        for child in children {
+            text.push(' ');
+            text.push_str(&child.to_scene_string());
+        }
+        text.push(')');
+        return text;
+    }
+
+    pub fn bounding_box(&self) -> BoundingBox {
+        match self {
+            CsgNode::Primitive(primitive, transform) => {
+                let e = transform.world_extents(&primitive.local_extents());
+                let o = &transform.origin;
+                return ((o.x - e.x, o.x + e.x), (o.y - e.y, o.y + e.y), (o.z - e.z, o.z + e.z));
+            },
+            CsgNode::Union(children) => {
+                let mut bb = children[0].bounding_box();
+                for child in &children[1..] {
+                    let c = child.bounding_box();
+                    bb = (
+                        (bb.0.0.min(c.0.0), bb.0.1.max(c.0.1)),
+                        (bb.1.0.min(c.1.0), bb.1.1.max(c.1.1)),
+                        (bb.2.0.min(c.2.0), bb.2.1.max(c.2.1))
+                    );
+                }
+                return bb;
+            },
+            CsgNode::Intersection(children) => {
+                let mut bb = children[0].bounding_box();
+                for child in &children[1..] {
+                    let c = child.bounding_box();
+                    bb = (
+                        (bb.0.0.max(c.0.0), bb.0.1.min(c.0.1)),
+                        (bb.1.0.max(c.1.0), bb.1.1.min(c.1.1)),
+                        (bb.2.0.max(c.2.0), bb.2.1.min(c.2.1))
+                    );
+                }
+                return bb;
+            },
+            CsgNode::Difference(children) => {
+                return children[0].bounding_box();
+            },
+            CsgNode::Surface(child) => {
+                return child.bounding_box();
+            }
+        }
+    }
+
+    pub fn translated(&self, offset: &Vector3Float) -> CsgNode {
+        match self {
+            CsgNode::Primitive(primitive, transform) => {
+                let origin = Vector3Float::new(transform.origin.x + offset.x, transform.origin.y + offset.y, transform.origin.z + offset.z);
+                return CsgNode::Primitive(primitive.clone(), Transform::with_rotation(origin, transform.rotation));
+            },
+            CsgNode::Union(children) => CsgNode::Union(children.iter().map(|c| c.translated(offset)).collect()),
+            CsgNode::Intersection(children) => CsgNode::Intersection(children.iter().map(|c| c.translated(offset)).collect()),
+            CsgNode::Difference(children) => CsgNode::Difference(children.iter().map(|c| c.translated(offset)).collect()),
+            CsgNode::Surface(child) => CsgNode::Surface(Box::new(child.translated(offset)))
+        }
+    }
+
+    /// Number of values the kernel has to keep on its stack while evaluating this node.
+    pub fn stack_depth(&self) -> usize {
+        match self {
+            CsgNode::Primitive(_, _) => 1,
+            CsgNode::Surface(child) => child.stack_depth(),
+            CsgNode::Union(children) | CsgNode::Intersection(children) | CsgNode::Difference(children) => {
+                let mut depth = children[0].stack_depth();
+                for child in &children[1..] {
+                    depth = depth.max(1 + child.stack_depth());
+                }
+                return depth;
+            }
+        }
+    }
+
+    /// Appends the node to the buffer in postfix order, n-ary operations are
+    /// folded into a chain of binary ones. Returns the number of nodes written.
+    pub fn encode(&self, nodes: &mut Vec<f32>) -> usize {
+        let (op, children) = match self {
+            CsgNode::Primitive(primitive, transform) => {
+                primitive.encode(transform, nodes);
+                return 1;
+            },
+            CsgNode::Union(children) => (OP_UNION, children),
+            CsgNode::Intersection(children) => (OP_INTERSECTION, children),
+            CsgNode::Difference(children) => (OP_DIFFERENCE, children),
+            CsgNode::Surface(child) => {
+                let count = child.encode(nodes);
+                nodes.push(OP_SURFACE);
+                nodes.extend_from_slice(&[0.0; NODE_STRIDE - 1]);
+                return count + 1;
+            }
+        };
+
+        let mut count = children[0].encode(nodes);
+        for child in &children[1..] {
+            count += child.encode(nodes);
+            nodes.push(op);
+            nodes.extend_from_slice(&[0.0; NODE_STRIDE - 1]);
+            count += 1;
+        }
+        return count;
+    }
+}
@@ -2,7 +2,7 @@ use std::{ptr};
 
 use opencl3::{device::{CL_DEVICE_TYPE_GPU, Device}, context::{Context}, command_queue::{CommandQueue, CL_QUEUE_PROFILING_ENABLE}, program::Program, kernel::{Kernel, ExecuteKernel}, types::{cl_float, CL_BLOCKING, cl_event, cl_uint, cl_int, CL_NON_BLOCKING}, memory::{Buffer, CL_MEM_READ_ONLY, CL_MEM_WRITE_ONLY}, platform::get_platforms};
 
-use crate::{GeneratorData, sphere::{Sphere}};
+use crate::{GeneratorData, VolumeTextures, instance::{Instance, encode_instances}, overlap, texture};
 
 fn init() -> Result<(Context, CommandQueue, Kernel), String> {
     let platforms = match get_platforms() {
@@ -70,7 +70,7 @@ fn init() -> Result<(Context, CommandQueue, Kernel), String> {
     return Ok((context, queue, kernel));
 }
 
-pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Sphere>) -> (Vec<u32>, Vec<u32>) {
+pub fn generate_volume_textures_cl(gen_data: &GeneratorData, instances: &Vec<Instance>, interior: Option<&Instance>) -> VolumeTextures {
     let size_x = gen_data.pixel_dimensions.x;
     let size_y = gen_data.pixel_dimensions.y;
     let size_z = gen_data.pixel_dimensions.z;
@@ -89,15 +89,22 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
         }
     };
 
-    let mut spheres_vec = Vec::with_capacity(spheres.len() * 6);
+    // The container interior is encoded after the instances
+    let (mut instance_vec, mut node_vec) = match interior {
+        Some(interior) => {
+            let mut encoded = instances.clone();
+            encoded.push(interior.clone());
+            encode_instances(&encoded)
+        },
+        None => encode_instances(instances)
+    };
 
-    for sphere in spheres {
-        spheres_vec.push(sphere.origin().x);
-        spheres_vec.push(sphere.origin().y);
-        spheres_vec.push(sphere.origin().z);
-        spheres_vec.push(sphere.radius());
-        spheres_vec.push(sphere.id() as f32);
-        spheres_vec.push(sphere.density() as f32);
+    // OpenCL does not allow empty buffers
+    if instance_vec.is_empty() {
+        instance_vec.push(0.0);
+    }
+    if node_vec.is_empty() {
+        node_vec.push(0.0);
     }
 
     /*let mut rand_vec: Vec<f32> = Vec::with_capacity(size);
@@ -106,8 +113,16 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
     }*/
 
     let empty_space: cl_uint = gen_data.empty_space;
-    let quality: cl_uint = gen_data.noise_span;
-    let sphere_count: cl_int = spheres_vec.len() as i32;
+    let instance_count: cl_int = instances.len() as i32;
+    let has_interior: cl_int = interior.is_some() as i32;
+    let spacing = gen_data.spacing();
+    let (spacing_x, spacing_y, spacing_z): (cl_float, cl_float, cl_float) = (spacing.x, spacing.y, spacing.z);
+    let overlap_mode: cl_int = overlap::overlap_mode(gen_data.overlap.as_ref());
+    let count_overlaps: cl_int = gen_data.overlap_map as i32;
+    let supersampling: cl_int = gen_data.supersampling as i32;
+    let texture_seed: cl_uint = rand::random::<u32>();
+    let max_density: cl_float = gen_data.max_density();
+    let texture_vec = texture::encode_textures(gen_data.texture.as_ref(), gen_data.background_texture.as_ref());
 
     let mut dim_buffer = unsafe {
         match Buffer::<cl_uint>::create(&context, CL_MEM_READ_ONLY, 3, ptr::null_mut()) {
@@ -117,8 +132,24 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
             }
         }
     };
-    let mut sph_buffer = unsafe {
-        match Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, spheres_vec.len(), ptr::null_mut()) {
+    let mut ins_buffer = unsafe {
+        match Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, instance_vec.len(), ptr::null_mut()) {
+            Ok(b) => b,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
+    let mut texture_buffer = unsafe {
+        match Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, texture_vec.len(), ptr::null_mut()) {
+            Ok(b) => b,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
+    let mut node_buffer = unsafe {
+        match Buffer::<cl_float>::create(&context, CL_MEM_READ_ONLY, node_vec.len(), ptr::null_mut()) {
             Ok(b) => b,
             Err(e) => {
                 panic!("{}", e);
@@ -127,7 +158,7 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
     };
     
     let den_buffer = unsafe {
-        match Buffer::<cl_uint>::create(&context, CL_MEM_WRITE_ONLY, size, ptr::null_mut()) {
+        match Buffer::<cl_float>::create(&context, CL_MEM_WRITE_ONLY, size, ptr::null_mut()) {
             Ok(b) => b,
             Err(e) => {
                 panic!("{}", e);
@@ -142,6 +173,22 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
             }
         }
     };
+    let overlap_buffer = unsafe {
+        match Buffer::<cl_uint>::create(&context, CL_MEM_WRITE_ONLY, size, ptr::null_mut()) {
+            Ok(b) => b,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
+    let occupancy_buffer = unsafe {
+        match Buffer::<cl_float>::create(&context, CL_MEM_WRITE_ONLY, size, ptr::null_mut()) {
+            Ok(b) => b,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
 
     unsafe {
         match queue.enqueue_write_buffer(&mut dim_buffer, CL_BLOCKING, 0, &dims, &[]) {
@@ -152,8 +199,26 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
         };
     }
 
+    unsafe {
+        match queue.enqueue_write_buffer(&mut ins_buffer, CL_BLOCKING, 0, &instance_vec, &[]) {
+            Ok(_) => (),
+            Err(e) => {
+                panic!("{}", e);
+            }
+        };
+    }
+
+    unsafe {
+        match queue.enqueue_write_buffer(&mut texture_buffer, CL_BLOCKING, 0, &texture_vec, &[]) {
+            Ok(_) => (),
+            Err(e) => {
+                panic!("{}", e);
+            }
+        };
+    }
+
     let wait_event = unsafe {
-        match queue.enqueue_write_buffer(&mut sph_buffer, CL_BLOCKING, 0, &spheres_vec, &[]) {
+        match queue.enqueue_write_buffer(&mut node_buffer, CL_BLOCKING, 0, &node_vec, &[]) {
             Ok(q) => q,
             Err(e) => {
                 panic!("{}", e);
@@ -164,12 +229,24 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
     let kernel_event = unsafe {
         match ExecuteKernel::new(&kernel)
             .set_arg(&empty_space)
-            .set_arg(&quality)
-            .set_arg(&sphere_count)
+            .set_arg(&instance_count)
+            .set_arg(&has_interior)
+            .set_arg(&spacing_x)
+            .set_arg(&spacing_y)
+            .set_arg(&spacing_z)
+            .set_arg(&overlap_mode)
+            .set_arg(&count_overlaps)
+            .set_arg(&supersampling)
+            .set_arg(&texture_seed)
+            .set_arg(&max_density)
             .set_arg(&dim_buffer)
-            .set_arg(&sph_buffer)
+            .set_arg(&ins_buffer)
+            .set_arg(&node_buffer)
+            .set_arg(&texture_buffer)
             .set_arg(&den_buffer)
             .set_arg(&mat_buffer)
+            .set_arg(&overlap_buffer)
+            .set_arg(&occupancy_buffer)
             .set_global_work_size(size)
             .set_wait_event(&wait_event)
             .enqueue_nd_range(&queue) {
@@ -183,8 +260,10 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
     let mut events: Vec<cl_event> = Vec::default();
     events.push(kernel_event.get());
 
-    let mut den_result: Vec<cl_uint> = vec![0; size];
+    let mut den_result: Vec<cl_float> = vec![0.0; size];
     let mut mat_result: Vec<cl_uint> = vec![0; size];
+    let mut overlap_result: Vec<cl_uint> = vec![0; size];
+    let mut occupancy_result: Vec<cl_float> = vec![0.0; size];
 
     let read_event_1 = unsafe {
         match queue.enqueue_read_buffer(&den_buffer, CL_NON_BLOCKING, 0, &mut den_result, &events) {
@@ -203,9 +282,34 @@ pub fn generate_volume_textures_cl(gen_data: &GeneratorData, spheres: &Vec<Spher
         }
     };
 
+    let read_event_3 = unsafe {
+        match queue.enqueue_read_buffer(&overlap_buffer, CL_NON_BLOCKING, 0, &mut overlap_result, &events) {
+            Ok(r) => r,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
+
+    let read_event_4 = unsafe {
+        match queue.enqueue_read_buffer(&occupancy_buffer, CL_NON_BLOCKING, 0, &mut occupancy_result, &events) {
+            Ok(r) => r,
+            Err(e) => {
+                panic!("{}", e);
+            }
+        }
+    };
+
     read_event_1.wait().unwrap();
     read_event_2.wait().unwrap();
+    read_event_3.wait().unwrap();
+    read_event_4.wait().unwrap();
 
     println!("Generated material texture and density texture");
-    return (mat_result, den_result);
+    return VolumeTextures {
+        materials: mat_result,
+        densities: den_result,
+        overlaps: overlap_result,
+        occupancy: occupancy_result
+    };
 }
\ No newline at end of file
@@ -0,0 +1,232 @@
+//! Reads the `.csv` files written by the generator back, so the same geometry can be
+//! rendered again at another resolution or noise level. Sphere data gives spheres,
+//! instance data (with a `SHAPE` column in the scene file syntax) gives any instances.
+
+use std::{fs, io::{Error, ErrorKind}};
+
+use crate::{instance::Instance, lattice::LatticeSite, scene, sphere::Sphere, vector3float::Vector3Float, profile::Profile};
+
+fn csv_error(filename: &str, line: usize, message: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, format!("{} line {}: {}", filename, line, message));
+}
+
+/// Value of the named column, parsed.
+fn field<T: std::str::FromStr>(header: &Vec<&str>, values: &Vec<&str>, name: &str, filename: &str, line: usize) -> Result<T, Error> {
+    let index = match header.iter().position(|h| *h == name) {
+        Some(i) => i,
+        None => {
+            return Err(csv_error(filename, 1, &format!("missing column {}", name)));
+        }
+    };
+    match values.get(index).map(|v| v.parse()) {
+        Some(Ok(v)) => {
+            return Ok(v);
+        },
+        _ => {
+            return Err(csv_error(filename, line, &format!("invalid {}", name)));
+        }
+    }
+}
+
+/// Density profile of the PROFILE columns, None for flat instances or files without them.
+fn parse_profile(header: &Vec<&str>, values: &Vec<&str>, filename: &str, line: usize, depth: Option<f32>) -> Result<Option<Profile>, Error> {
+    if !header.contains(&"PROFILE") {
+        return Ok(None);
+    }
+    let kind: String = field(header, values, "PROFILE", filename, line)?;
+    if kind == "flat" {
+        return Ok(None);
+    }
+    let depth = match depth {
+        Some(d) => d,
+        None => field(header, values, "PROFILE DEPTH", filename, line)?
+    };
+    return Ok(Some(Profile {
+        kind,
+        parameters: [field(header, values, "PROFILE A", filename, line)?, field(header, values, "PROFILE B", filename, line)?],
+        depth
+    }));
+}
+
+fn parse_sphere(header: &Vec<&str>, values: &Vec<&str>, filename: &str, line: usize) -> Result<Sphere, Error> {
+    let id = field(header, values, "ID", filename, line)?;
+    let origin = Vector3Float::new(
+        field(header, values, "POSITION X", filename, line)?,
+        field(header, values, "POSITION Y", filename, line)?,
+        field(header, values, "POSITION Z", filename, line)?
+    );
+    let radius = field(header, values, "RADIUS", filename, line)?;
+    let profile = parse_profile(header, values, filename, line, Some(radius))?;
+    let sphere = Sphere::new(id, radius, origin, field(header, values, "DENSITY", filename, line)?).with_profile(profile);
+
+    if !header.contains(&"LATTICE I") {
+        return Ok(sphere);
+    }
+    let site = LatticeSite {
+        index: [
+            field(header, values, "LATTICE I", filename, line)?,
+            field(header, values, "LATTICE J", filename, line)?,
+            field(header, values, "LATTICE K", filename, line)?
+        ],
+        basis: field(header, values, "BASIS", filename, line)?,
+        substituted: field::<u8>(header, values, "SUBSTITUTED", filename, line)? != 0
+    };
+    return Ok(sphere.with_lattice_site(site));
+}
+
+fn parse_instance(header: &Vec<&str>, values: &Vec<&str>, filename: &str, line: usize, voxel_size: f32) -> Result<Instance, Error> {
+    let id = field(header, values, "ID", filename, line)?;
+    let density = field(header, values, "DENSITY", filename, line)?;
+    let thickness: f32 = field(header, values, "THICKNESS", filename, line)?;
+    let class: String = field(header, values, "CLASS", filename, line)?;
+
+    // The shape is the last column and has no commas
+    let shape = match scene::parse_shape(values[header.len() - 1]) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(csv_error(filename, line, &e.to_string()));
+        }
+    };
+
+    let instance = if thickness > 0.0 {
+        Instance::new_thin(id, density, shape, thickness, voxel_size)
+    } else {
+        Instance::new(id, density, shape)
+    };
+    let profile = parse_profile(header, values, filename, line, None)?;
+    return Ok(instance.with_class(&class).with_profile(profile));
+}
+
+/// Loads the instances of a sphere or instance data file. Spheres are also returned,
+/// so their data is written again in the same form.
+pub fn load_instance_data(filename: &str, voxel_size: f32) -> Result<(Vec<Instance>, Option<Vec<Sphere>>), Error> {
+    let contents = fs::read_to_string(filename)?;
+    let mut lines = contents.lines();
+    let header: Vec<&str> = match lines.next() {
+        Some(h) => h.split(',').collect(),
+        None => {
+            return Err(csv_error(filename, 1, "empty file"));
+        }
+    };
+    let is_sphere_data = header.contains(&"RADIUS");
+
+    let mut spheres = Vec::new();
+    let mut instances = Vec::new();
+    for (index, line) in lines.enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let values: Vec<&str> = line.split(',').collect();
+        if values.len() != header.len() {
+            return Err(csv_error(filename, index + 2, &format!("expected {} columns", header.len())));
+        }
+        if is_sphere_data {
+            spheres.push(parse_sphere(&header, &values, filename, index + 2)?);
+        } else {
+            instances.push(parse_instance(&header, &values, filename, index + 2, voxel_size)?);
+        }
+    }
+
+    println!("Loaded {} instances from {}", spheres.len() + instances.len(), filename);
+    if is_sphere_data {
+        spheres.sort();
+        let instances = spheres.iter().map(|s| s.to_instance()).collect();
+        return Ok((instances, Some(spheres)));
+    }
+    return Ok((instances, None));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::csg::{CsgNode, Primitive, Transform, MAX_STACK_DEPTH};
+
+    fn round_trip(instances: &Vec<Instance>, name: &str) -> Vec<Instance> {
+        let path = std::env::temp_dir().join(format!("volume-generator-{}-{}.csv", name, std::process::id()));
+        let filename = path.to_str().unwrap();
+        crate::write_instance_data(filename, instances).unwrap();
+        let loaded = load_instance_data(filename, 0.01);
+        fs::remove_file(filename).unwrap();
+        let (loaded, spheres) = loaded.unwrap();
+        assert!(spheres.is_none());
+        assert_eq!(loaded.len(), instances.len());
+        return loaded;
+    }
+
+    fn assert_same_shape(a: &Instance, b: &Instance) {
+        for i in 0..20 {
+            let p = Vector3Float::new(0.05 * i as f32, 0.3 + 0.02 * i as f32, 0.7 - 0.03 * i as f32);
+            assert!((a.shape().signed_distance(&p) - b.shape().signed_distance(&p)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn instance_data_round_trips() {
+        let ellipsoid = CsgNode::Primitive(Primitive::Ellipsoid { radii: Vector3Float::new(0.2, 0.1, 0.15) },
+            Transform::with_euler_angles(Vector3Float::new(0.4, 0.5, 0.6), &Vector3Float::new(10.0, 20.0, 30.0)));
+        let hollow = CsgNode::Difference(vec![
+            CsgNode::Primitive(Primitive::Cuboid { half_extents: Vector3Float::new(0.2, 0.2, 0.2) }, Transform::new(Vector3Float::new(0.5, 0.5, 0.5))),
+            CsgNode::Primitive(Primitive::Cylinder { radius: 0.1, half_height: 0.3 }, Transform::new(Vector3Float::new(0.5, 0.5, 0.5)))
+        ]);
+        let sheet = CsgNode::Surface(Box::new(CsgNode::Primitive(Primitive::Sphere { radius: 0.3 }, Transform::new(Vector3Float::new(0.5, 0.5, 0.5)))));
+        let instances = vec![
+            Instance::new(1, 120, ellipsoid).with_class("phantom"),
+            Instance::new(2, 80, hollow),
+            Instance::new_thin(3, 200, sheet, 0.02, 0.01)
+        ];
+
+        let loaded = round_trip(&instances, "instances");
+        for (a, b) in instances.iter().zip(loaded.iter()) {
+            assert_eq!(a.id(), b.id());
+            assert_eq!(a.class(), b.class());
+            assert_eq!(a.density(), b.density());
+            assert_eq!(a.thickness(), b.thickness());
+            assert!(b.profile().is_none());
+            assert_same_shape(a, b);
+        }
+    }
+
+    /// Unions nested to the given evaluation stack depth, each level adds one sphere on the stack.
+    fn nested_shape(depth: usize) -> String {
+        let mut shape = String::from("(sphere 0.5 0.5 0.5 0.1)");
+        for _ in 1..depth {
+            shape = format!("(union (sphere 0.5 0.5 0.5 0.1) {})", shape);
+        }
+        return shape;
+    }
+
+    #[test]
+    fn shapes_deeper_than_the_stack_are_rejected() {
+        for (depth, accepted) in [(MAX_STACK_DEPTH, true), (MAX_STACK_DEPTH + 1, false)] {
+            let path = std::env::temp_dir().join(format!("volume-generator-depth{}-{}.csv", depth, std::process::id()));
+            let filename = path.to_str().unwrap();
+            let data = format!("ID,CLASS,DENSITY,THICKNESS,MIN X,MIN Y,MIN Z,MAX X,MAX Y,MAX Z,SHAPE\n1,scene,100,0,0,0,0,1,1,1,{}\n", nested_shape(depth));
+            fs::write(filename, data).unwrap();
+            let loaded = load_instance_data(filename, 0.01);
+            fs::remove_file(filename).unwrap();
+            assert_eq!(loaded.is_ok(), accepted, "shape of stack depth {}", depth);
+        }
+    }
+
+    #[test]
+    fn profiles_round_trip() {
+        let sphere = CsgNode::Primitive(Primitive::Sphere { radius: 0.2 }, Transform::new(Vector3Float::new(0.5, 0.5, 0.5)));
+        let profile = Profile {
+            kind: String::from("core-shell"),
+            parameters: [0.6, 40.0],
+            depth: 0.2
+        };
+        let instances = vec![
+            Instance::new(1, 90, sphere.clone()).with_profile(Some(profile)),
+            Instance::new(2, 60, sphere)
+        ];
+
+        let loaded = round_trip(&instances, "profiles");
+        let p = loaded[0].profile().unwrap();
+        assert_eq!(p.kind, "core-shell");
+        assert_eq!(p.parameters, [0.6, 40.0]);
+        assert_eq!(p.depth, 0.2);
+        assert!(loaded[1].profile().is_none());
+    }
+}
@@ -0,0 +1,218 @@
+use crate::{csg::{CsgNode, BoundingBox, NODE_STRIDE}, vector3float::Vector3Float, profile::Profile};
+
+/// Number of floats used by one encoded instance: id, density, first node,
+/// node count, thickness, the bounding box (6) and the density profile (4).
+pub const INSTANCE_STRIDE: usize = 15;
+
+/// A single labelled object in the volume, described by a CSG tree.
+/// Thin instances have a thickness around the zero level of their shape,
+/// so they are usually built from surface nodes.
+#[derive(Clone)]
+pub struct Instance {
+    id: u32,
+    density: u32,
+    class: String,
+    shape: CsgNode,
+    thickness: f32,
+    bounding_box: BoundingBox,
+    profile: Option<Profile>
+}
+
+impl Instance {
+    pub fn new(id: u32, density: u32, shape: CsgNode) -> Self {
+        let bounding_box = shape.bounding_box();
+        return Self {
+            id,
+            density,
+            class: String::from("object"),
+            shape,
+            thickness: 0.0,
+            bounding_box,
+            profile: None
+        }
+    }
+
+    /// Creates a sheet of the given thickness. The bounding box also covers voxels
+    /// of the given size that are only partially filled by the sheet.
+    pub fn new_thin(id: u32, density: u32, shape: CsgNode, thickness: f32, voxel_size: f32) -> Self {
+        let bb = shape.bounding_box();
+        let m = (thickness + voxel_size) / 2.0;
+        return Self {
+            id,
+            density,
+            class: String::from("sheet"),
+            shape,
+            thickness,
+            bounding_box: ((bb.0.0 - m, bb.0.1 + m), (bb.1.0 - m, bb.1.1 + m), (bb.2.0 - m, bb.2.1 + m)),
+            profile: None
+        }
+    }
+
+    pub fn with_class(mut self, class: &str) -> Self {
+        self.class = String::from(class);
+        return self;
+    }
+
+    pub fn with_density(mut self, density: u32) -> Self {
+        self.density = density;
+        return self;
+    }
+
+    pub fn with_profile(mut self, profile: Option<Profile>) -> Self {
+        self.profile = profile;
+        return self;
+    }
+
+    pub fn id(&self) -> u32 {
+        return self.id;
+    }
+
+    pub fn density(&self) -> u32 {
+        return self.density;
+    }
+
+    pub fn class(&self) -> &str {
+        return &self.class;
+    }
+
+    pub fn shape(&self) -> &CsgNode {
+        return &self.shape;
+    }
+
+    pub fn thickness(&self) -> f32 {
+        return self.thickness;
+    }
+
+    pub fn is_thin(&self) -> bool {
+        return self.thickness > 0.0;
+    }
+
+    pub fn bounding_box(&self) -> &BoundingBox {
+        return &self.bounding_box;
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        return self.profile.as_ref();
+    }
+
+    /// Copy of the instance moved by the offset, keeping its id.
+    pub fn translated(&self, offset: &Vector3Float) -> Instance {
+        let bb = &self.bounding_box;
+        return Self {
+            id: self.id,
+            density: self.density,
+            class: self.class.clone(),
+            shape: self.shape.translated(offset),
+            thickness: self.thickness,
+            bounding_box: (
+                (bb.0.0 + offset.x, bb.0.1 + offset.x),
+                (bb.1.0 + offset.y, bb.1.1 + offset.y),
+                (bb.2.0 + offset.z, bb.2.1 + offset.z)
+            ),
+            profile: self.profile.clone()
+        }
+    }
+
+    fn in_bounding_box(&self, p: &Vector3Float) -> bool {
+        let bb = &self.bounding_box;
+        return p.x >= bb.0.0 && p.x <= bb.0.1 && p.y >= bb.1.0 && p.y <= bb.1.1 && p.z >= bb.2.0 && p.z <= bb.2.1;
+    }
+
+    pub fn contains(&self, p: &Vector3Float) -> bool {
+        if !self.in_bounding_box(p) {
+            return false;
+        }
+        return self.shape.signed_distance(p) <= 0.0;
+    }
+
+    /// Density at a point inside the instance, following its density profile up to the largest density.
+    pub fn density_at(&self, p: &Vector3Float, max_density: f32) -> f32 {
+        match &self.profile {
+            Some(profile) => profile.density(self.density as f32, self.shape.signed_distance(p), max_density),
+            None => self.density as f32
+        }
+    }
+
+    /// Fraction of a voxel centered at the point covered by a thin instance.
+    pub fn coverage(&self, p: &Vector3Float, voxel_size: f32) -> f32 {
+        if !self.in_bounding_box(p) {
+            return 0.0;
+        }
+        return slab_coverage(self.shape.signed_distance(p), self.thickness, voxel_size);
+    }
+}
+
+/// Adds periodic images of instances crossing the volume faces, so they wrap around
+/// and the volume tiles seamlessly. Images follow their instance to keep the rendering order.
+pub fn add_periodic_images(instances: Vec<Instance>, extent: &Vector3Float) -> Vec<Instance> {
+    let mut wrapped = Vec::with_capacity(instances.len());
+
+    for instance in instances {
+        let bb = instance.bounding_box;
+        let shifts = |min: f32, max: f32, size: f32| {
+            let mut s = vec![0.0];
+            if min < 0.0 {
+                s.push(size);
+            }
+            if max > size {
+                s.push(-size);
+            }
+            return s;
+        };
+        let (xs, ys, zs) = (shifts(bb.0.0, bb.0.1, extent.x), shifts(bb.1.0, bb.1.1, extent.y), shifts(bb.2.0, bb.2.1, extent.z));
+
+        let mut images = Vec::new();
+        for x in &xs {
+            for y in &ys {
+                for z in &zs {
+                    if *x != 0.0 || *y != 0.0 || *z != 0.0 {
+                        images.push(instance.translated(&Vector3Float::new(*x, *y, *z)));
+                    }
+                }
+            }
+        }
+        wrapped.push(instance);
+        wrapped.extend(images);
+    }
+
+    return wrapped;
+}
+
+/// Part of a voxel of size h covered by a slab of thickness t, whose middle is at distance d
+/// from the voxel center, measured along the slab normal. Matches slab_coverage in kernel.cl.
+pub fn slab_coverage(d: f32, t: f32, h: f32) -> f32 {
+    let low = (d - t / 2.0).max(-h / 2.0);
+    let high = (d + t / 2.0).min(h / 2.0);
+    return (high - low).max(0.0) / h;
+}
+
+/// Thin instances label a voxel when they cover at least half of what they could cover at most,
+/// so sheets thinner than a voxel still form a connected label.
+pub fn thin_label_threshold(t: f32, h: f32) -> f32 {
+    return 0.5 * t.min(h) / h;
+}
+
+/// Flattens instances into the instance and node buffers read by kernel.cl.
+pub fn encode_instances(instances: &Vec<Instance>) -> (Vec<f32>, Vec<f32>) {
+    let mut instance_vec = Vec::with_capacity(instances.len() * INSTANCE_STRIDE);
+    let mut node_vec = Vec::with_capacity(instances.len() * NODE_STRIDE);
+
+    for instance in instances {
+        let start = node_vec.len() / NODE_STRIDE;
+        let count = instance.shape.encode(&mut node_vec);
+        let bb = &instance.bounding_box;
+
+        instance_vec.push(instance.id as f32);
+        instance_vec.push(instance.density as f32);
+        instance_vec.push(start as f32);
+        instance_vec.push(count as f32);
+        instance_vec.push(instance.thickness);
+        instance_vec.extend_from_slice(&[bb.0.0, bb.1.0, bb.2.0, bb.0.1, bb.1.1, bb.2.1]);
+        match &instance.profile {
+            Some(profile) => instance_vec.extend_from_slice(&[profile.code(), profile.parameters[0], profile.parameters[1], profile.depth]),
+            None => instance_vec.extend_from_slice(&[0.0; 4])
+        }
+    }
+
+    return (instance_vec, node_vec);
+}
@@ -0,0 +1,395 @@
+//! Placement intensity fields make instances more likely in some parts of the volume.
+//! A field is either an analytic expression of the position, for example `x` for a
+//! gradient along x or `exp(-r^2 / 0.02)` for a concentration near the centre,
+//! or an 8 bit `.raw` mask with the dimensions of the volume, whose voxel values are relative intensities.
+//!
+//! Expressions use `x`, `y`, `z` (fractions of the volume extent), `r` (distance from
+//! the centre in the same fractions), numbers, `+ - * / ^`, parentheses and the functions
+//! `exp`, `sqrt`, `abs`, `sin`, `cos`, `min` and `max`. Negative values are treated as zero.
+
+use std::{cell::Cell, fs, io::{Error, ErrorKind}};
+
+use rand::Rng;
+
+use crate::{GeneratorData, vector3float::Vector3Float, vector3uint::Vector3Usize};
+
+/// Points per axis the expression is evaluated at to find its maximum.
+const MAXIMUM_SAMPLES: usize = 32;
+/// Factor on the sampled maximum of an expression, for peaks between the sample points.
+const MAXIMUM_MARGIN: f32 = 1.5;
+
+const FUNCTION_NAMES: [&str; 7] = ["exp", "sqrt", "abs", "sin", "cos", "min", "max"];
+
+pub enum Expression {
+    Number(f32),
+    Variable(char),
+    Negate(Box<Expression>),
+    Binary(char, Box<Expression>, Box<Expression>),
+    Function(String, Vec<Expression>)
+}
+
+impl Expression {
+    fn evaluate(&self, p: &Vector3Float) -> f32 {
+        match self {
+            Expression::Number(n) => *n,
+            Expression::Variable('x') => p.x,
+            Expression::Variable('y') => p.y,
+            Expression::Variable('z') => p.z,
+            Expression::Variable(_) => {
+                let centre = Vector3Float::new(0.5, 0.5, 0.5);
+                Vector3Float::sub(p, &centre).length()
+            },
+            Expression::Negate(e) => -e.evaluate(p),
+            Expression::Binary(op, a, b) => {
+                let (a, b) = (a.evaluate(p), b.evaluate(p));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    _ => a.powf(b)
+                }
+            },
+            Expression::Function(name, args) => {
+                let a = args[0].evaluate(p);
+                match name.as_str() {
+                    "exp" => a.exp(),
+                    "sqrt" => a.sqrt(),
+                    "abs" => a.abs(),
+                    "sin" => a.sin(),
+                    "cos" => a.cos(),
+                    "min" => args[1..].iter().fold(a, |m, e| m.min(e.evaluate(p))),
+                    _ => args[1..].iter().fold(a, |m, e| m.max(e.evaluate(p)))
+                }
+            }
+        }
+    }
+}
+
+fn expression_error(message: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, format!("Intensity expression: {}", message));
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.chars.len() && self.chars[self.position].is_whitespace() {
+            self.position += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        return self.chars.get(self.position).copied();
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), Error> {
+        if self.peek() == Some(c) {
+            self.position += 1;
+            return Ok(());
+        }
+        return Err(expression_error(&format!("expected '{}' at position {}", c, self.position + 1)));
+    }
+
+    fn sum(&mut self) -> Result<Expression, Error> {
+        let mut left = self.product()?;
+        while let Some(op) = self.peek().filter(|c| *c == '+' || *c == '-') {
+            self.position += 1;
+            let right = self.product()?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        return Ok(left);
+    }
+
+    fn product(&mut self) -> Result<Expression, Error> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.peek().filter(|c| *c == '*' || *c == '/') {
+            self.position += 1;
+            let right = self.unary()?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+        return Ok(left);
+    }
+
+    fn unary(&mut self) -> Result<Expression, Error> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+        let base = self.atom()?;
+        if self.peek() == Some('^') {
+            self.position += 1;
+            let exponent = self.unary()?;
+            return Ok(Expression::Binary('^', Box::new(base), Box::new(exponent)));
+        }
+        return Ok(base);
+    }
+
+    fn atom(&mut self) -> Result<Expression, Error> {
+        let start = self.position;
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let e = self.sum()?;
+                self.expect(')')?;
+                return Ok(e);
+            },
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.position;
+                while self.position < self.chars.len() && (self.chars[self.position].is_ascii_digit() || self.chars[self.position] == '.') {
+                    self.position += 1;
+                }
+                let word: String = self.chars[start..self.position].iter().collect();
+                match word.parse() {
+                    Ok(n) => {
+                        return Ok(Expression::Number(n));
+                    },
+                    Err(_) => {
+                        return Err(expression_error(&format!("'{}' is not a valid number", word)));
+                    }
+                }
+            },
+            Some(c) if c.is_ascii_alphabetic() => {
+                let start = self.position;
+                while self.position < self.chars.len() && self.chars[self.position].is_ascii_alphabetic() {
+                    self.position += 1;
+                }
+                let word: String = self.chars[start..self.position].iter().collect();
+                if ["x", "y", "z", "r"].contains(&word.as_str()) {
+                    return Ok(Expression::Variable(word.chars().next().unwrap()));
+                }
+                if !FUNCTION_NAMES.contains(&word.as_str()) {
+                    return Err(expression_error(&format!("unknown name '{}'", word)));
+                }
+
+                self.expect('(')?;
+                let mut args = vec![self.sum()?];
+                while self.peek() == Some(',') {
+                    self.position += 1;
+                    args.push(self.sum()?);
+                }
+                self.expect(')')?;
+                if args.len() > 1 && word != "min" && word != "max" {
+                    return Err(expression_error(&format!("'{}' takes one argument", word)));
+                }
+                return Ok(Expression::Function(word, args));
+            },
+            _ => {
+                return Err(expression_error(&format!("expected a value at position {}", start + 1)));
+            }
+        }
+    }
+}
+
+pub fn parse_expression(text: &str) -> Result<Expression, Error> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        position: 0
+    };
+    let e = parser.sum()?;
+    if parser.peek().is_some() {
+        return Err(expression_error(&format!("unexpected '{}' at position {}", parser.chars[parser.position], parser.position + 1)));
+    }
+    return Ok(e);
+}
+
+/// Relative placement intensity over the volume.
+pub enum IntensityField {
+    Uniform,
+    /// The maximum bounds the expression for thinning, it is raised when a larger value is found.
+    Expression {
+        expression: Expression,
+        maximum: Cell<f32>,
+        extent: Vector3Float
+    },
+    /// Indices of the non-zero voxels, and their values relative to the largest one.
+    Mask {
+        dims: Vector3Usize,
+        voxels: Vec<usize>,
+        weights: Vec<f32>,
+        extent: Vector3Float
+    }
+}
+
+impl IntensityField {
+    /// Builds the field from the expression or mask file of the generator data, a uniform field if none is given.
+    pub fn load(gen_data: &GeneratorData) -> Result<Self, Error> {
+        if let Some(filename) = &gen_data.intensity_mask {
+            let data = fs::read(filename)?;
+            let dims = &gen_data.pixel_dimensions;
+            if data.len() != dims.x * dims.y * dims.z {
+                return Err(Error::new(ErrorKind::InvalidData,
+                    format!("Mask {} has {} voxels, the volume has {}x{}x{}", filename, data.len(), dims.x, dims.y, dims.z)));
+            }
+
+            let voxels: Vec<usize> = (0..data.len()).filter(|i| data[*i] != 0).collect();
+            if voxels.is_empty() {
+                return Err(Error::new(ErrorKind::InvalidData, format!("Mask {} is empty", filename)));
+            }
+            // The bound is the largest value of all voxels, so no voxel is under-sampled
+            let maximum = voxels.iter().map(|i| data[*i]).max().unwrap_or(1) as f32;
+            let weights = voxels.iter().map(|i| data[*i] as f32 / maximum).collect();
+            println!("Loaded placement mask {} with {} voxels", filename, voxels.len());
+            return Ok(IntensityField::Mask {
+                dims: Vector3Usize::new(dims.x, dims.y, dims.z),
+                voxels,
+                weights,
+                extent: gen_data.extent.clone()
+            });
+        }
+
+        if let Some(text) = &gen_data.intensity {
+            let expression = parse_expression(text)?;
+            let n = MAXIMUM_SAMPLES;
+            let mut maximum = 0.0f32;
+            for k in 0..=n {
+                for j in 0..=n {
+                    for i in 0..=n {
+                        let p = Vector3Float::new(i as f32 / n as f32, j as f32 / n as f32, k as f32 / n as f32);
+                        let v = expression.evaluate(&p);
+                        if v.is_finite() {
+                            maximum = maximum.max(v);
+                        }
+                    }
+                }
+            }
+            if maximum <= 0.0 {
+                return Err(expression_error(&format!("'{}' is not positive anywhere in the volume", text)));
+            }
+            return Ok(IntensityField::Expression {
+                expression,
+                maximum: Cell::new(MAXIMUM_MARGIN * maximum),
+                extent: gen_data.extent.clone()
+            });
+        }
+
+        return Ok(IntensityField::Uniform);
+    }
+
+    fn value(&self, p: &Vector3Float) -> f32 {
+        match self {
+            IntensityField::Uniform => 1.0,
+            IntensityField::Expression { expression, maximum, extent } => {
+                let v = expression.evaluate(&Vector3Float::new(p.x / extent.x, p.y / extent.y, p.z / extent.z));
+                if !v.is_finite() {
+                    return 0.0;
+                }
+                // A peak missed by the sampled maximum raises the bound for all later points
+                if v > maximum.get() {
+                    println!("Warning: Placement intensity {} exceeds the sampled maximum {}, raising the bound", v, maximum.get());
+                    maximum.set(v);
+                }
+                v.max(0.0) / maximum.get()
+            },
+            IntensityField::Mask { dims, voxels, weights, extent } => {
+                let index = |v: f32, size: f32, n: usize| ((v / size * n as f32).max(0.0) as usize).min(n - 1);
+                let (i, j, k) = (index(p.x, extent.x, dims.x), index(p.y, extent.y, dims.y), index(p.z, extent.z, dims.z));
+                match voxels.binary_search(&((k * dims.y + j) * dims.x + i)) {
+                    Ok(index) => weights[index],
+                    Err(_) => 0.0
+                }
+            }
+        }
+    }
+
+    /// Thins candidate points, a point is kept with a probability proportional to the intensity.
+    pub fn accepts(&self, p: &Vector3Float) -> bool {
+        if let IntensityField::Uniform = self {
+            return true;
+        }
+        return rand::thread_rng().gen::<f32>() < self.value(p);
+    }
+
+    /// Point used when no candidate was accepted, a random voxel of a mask keeps instances inside it.
+    pub fn fallback(&self) -> Option<Vector3Float> {
+        match self {
+            IntensityField::Mask { dims, voxels, extent, .. } => {
+                let mut rand = rand::thread_rng();
+                let index = voxels[rand.gen_range(0..voxels.len())];
+                let (i, j, k) = (index % dims.x, (index / dims.x) % dims.y, index / (dims.x * dims.y));
+                return Some(Vector3Float::new(
+                    (i as f32 + rand.gen::<f32>()) / dims.x as f32 * extent.x,
+                    (j as f32 + rand.gen::<f32>()) / dims.y as f32 * extent.y,
+                    (k as f32 + rand.gen::<f32>()) / dims.z as f32 * extent.z
+                ));
+            },
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(text: &str, p: &Vector3Float) -> f32 {
+        match parse_expression(text) {
+            Ok(e) => e.evaluate(p),
+            Err(e) => panic!("'{}' did not parse: {}", text, e)
+        }
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        let p = Vector3Float::new(0.0, 0.0, 0.0);
+        assert_eq!(value("1 + 2 * 3", &p), 7.0);
+        assert_eq!(value("(1 + 2) * 3", &p), 9.0);
+        assert_eq!(value("8 / 4 / 2", &p), 1.0);
+        assert_eq!(value("10 - 4 - 3", &p), 3.0);
+        assert_eq!(value("2 ^ 3 ^ 2", &p), 512.0);
+        assert_eq!(value("-2 ^ 2", &p), -4.0);
+        assert_eq!(value("--1.5", &p), 1.5);
+    }
+
+    #[test]
+    fn variables_and_functions() {
+        let p = Vector3Float::new(0.25, 0.5, 1.0);
+        assert_eq!(value("x + y + z", &p), 1.75);
+        assert_eq!(value("r", &Vector3Float::new(0.5, 0.5, 0.5)), 0.0);
+        assert!((value("r", &Vector3Float::new(0.5, 0.5, 0.8)) - 0.3).abs() < 1e-6);
+        assert_eq!(value("min(x, y, z)", &p), 0.25);
+        assert_eq!(value("max(x, y, z)", &p), 1.0);
+        assert_eq!(value("sqrt(abs(-4))", &p), 2.0);
+        assert_eq!(value("exp(0) + cos(0) + sin(0)", &p), 2.0);
+    }
+
+    #[test]
+    fn peaks_above_the_bound_raise_it() {
+        // The peak lies between the sample points, so the sampled maximum misses it
+        let expression = parse_expression("exp(-((x - 0.51)^2 + (y - 0.51)^2 + (z - 0.51)^2) / 0.00001)").unwrap();
+        let field = IntensityField::Expression {
+            expression,
+            maximum: Cell::new(0.01),
+            extent: Vector3Float::new(1.0, 1.0, 1.0)
+        };
+        assert_eq!(field.value(&Vector3Float::new(0.51, 0.51, 0.51)), 1.0);
+        if let IntensityField::Expression { maximum, .. } = &field {
+            assert_eq!(maximum.get(), 1.0);
+        }
+        assert!(field.value(&Vector3Float::new(0.52, 0.51, 0.51)) < 1.0);
+    }
+
+    #[test]
+    fn mask_values_are_relative_intensities() {
+        let field = IntensityField::Mask {
+            dims: Vector3Usize::new(4, 1, 1),
+            voxels: vec![1, 3],
+            weights: vec![0.5, 1.0],
+            extent: Vector3Float::new(1.0, 1.0, 1.0)
+        };
+        assert_eq!(field.value(&Vector3Float::new(0.1, 0.5, 0.5)), 0.0);
+        assert_eq!(field.value(&Vector3Float::new(0.3, 0.5, 0.5)), 0.5);
+        assert_eq!(field.value(&Vector3Float::new(0.9, 0.5, 0.5)), 1.0);
+    }
+
+    #[test]
+    fn invalid_expressions_are_rejected() {
+        for text in ["", "1 +", "(x", "x)", "2 x", "foo(x)", "w", "exp(x, y)", "exp x", "1..2", "min()"] {
+            assert!(parse_expression(text).is_err(), "'{}' should not parse", text);
+        }
+    }
+}
@@ -4,80 +4,397 @@ typedef struct point {
     float z;
 } Point;
 
-float uniform_rand(int* seed) {
-    const int a = 16807;
-    const int m = 2147483647;
+#define NODE_STRIDE 16
+#define INSTANCE_STRIDE 15
+#define MAX_STACK_DEPTH 16
 
-    *seed = ((long) *seed * (long) a) % m;
-    
-    return (float) *seed / (float) m;
+#define OP_SPHERE 0
+#define OP_ELLIPSOID 1
+#define OP_CYLINDER 2
+#define OP_CUBOID 3
+#define OP_UNION 10
+#define OP_INTERSECTION 11
+#define OP_DIFFERENCE 12
+#define OP_SURFACE 13
+
+// Density profiles inside instances, see profile.rs
+#define PROFILE_FLAT 0
+#define PROFILE_LINEAR 1
+#define PROFILE_GAUSSIAN 2
+#define PROFILE_CORE_SHELL 3
+#define PROFILE_HALO 4
+
+// Procedural textures, see texture.rs
+#define TEXTURE_NONE 0
+#define TEXTURE_PERLIN 1
+#define TEXTURE_SIMPLEX 2
+#define TEXTURE_STRIDE 4
+
+// Overlap modes, see overlap.rs
+#define OVERLAP_FIRST 0
+#define OVERLAP_MAX_DENSITY 1
+#define OVERLAP_ADD 2
+#define OVERLAP_AVERAGE 3
+
+float primitive_distance(int op, float3 q, float3 params) {
+    if (op == OP_SPHERE) {
+        return length(q) - params.x;
+    } else if (op == OP_ELLIPSOID) {
+        float k0 = length(q / params);
+        float k1 = length(q / (params * params));
+        if (k1 == 0.0) {
+            return -min(params.x, min(params.y, params.z));
+        }
+        return k0 * (k0 - 1.0) / k1;
+    } else if (op == OP_CYLINDER) {
+        float2 d = { length(q.xy) - params.x, fabs(q.z) - params.y };
+        return min(max(d.x, d.y), 0.0f) + length(max(d, 0.0f));
+    } else {
+        float3 d = fabs(q) - params;
+        return min(max(d.x, max(d.y, d.z)), 0.0f) + length(max(d, 0.0f));
+    }
 }
 
-float normal_rand(float mu, float sig, int* seed) {
-    float u1 = uniform_rand(seed);
-    float u2 = uniform_rand(seed);
+float signed_distance(global float* nodes, int start, int count, float3 p) {
+    float stack[MAX_STACK_DEPTH];
+    int top = 0;
+
+    for (int n = start; n < start + count; n++) {
+        global float* node = nodes + n * NODE_STRIDE;
+        int op = (int) node[0];
+
+        if (op == OP_SURFACE) {
+            stack[top - 1] = fabs(stack[top - 1]);
+        } else if (op >= OP_UNION) {
+            float b = stack[--top];
+            float a = stack[top - 1];
+            if (op == OP_UNION) {
+                stack[top - 1] = min(a, b);
+            } else if (op == OP_INTERSECTION) {
+                stack[top - 1] = max(a, b);
+            } else {
+                stack[top - 1] = max(a, -b);
+            }
+        } else {
+            float3 d = p - (float3)(node[1], node[2], node[3]);
+            float3 q = {
+                node[4] * d.x + node[5] * d.y + node[6] * d.z,
+                node[7] * d.x + node[8] * d.y + node[9] * d.z,
+                node[10] * d.x + node[11] * d.y + node[12] * d.z
+            };
+            float3 params = { node[13], node[14], node[15] };
+            stack[top++] = primitive_distance(op, q, params);
+        }
+    }
+
+    return stack[0];
+}
+
+bool is_point_in_box(global float* instance, float3 p) {
+    return p.x >= instance[5] && p.y >= instance[6] && p.z >= instance[7]
+        && p.x <= instance[8] && p.y <= instance[9] && p.z <= instance[10];
+}
 
-    float r = sqrt(-2.0 * native_log(u1));
-    float cos = cospi(2.0 * u2);
-    return mu + sqrt(sig) * (r * cos);
+// Part of a voxel of size h covered by a slab of thickness t at distance d
+float slab_coverage(float d, float t, float h) {
+    float low = max(d - t / 2.0f, -h / 2.0f);
+    float high = min(d + t / 2.0f, h / 2.0f);
+    return max(high - low, 0.0f) / h;
 }
 
-bool is_point_in_sphere(float3 o, float3 p, float r) {
-    return distance(o, p) <= r;
+// Density of the instance at the signed distance d, following its profile
+float profile_density(global float* instance, float d, float max_density) {
+    int profile = (int) instance[11];
+    float density = instance[1];
+    float a = instance[12];
+    float b = instance[13];
+    float s = clamp(1.0f + d / max(instance[14], 1e-7f), 0.0f, 1.0f);
+    float value = density;
+    if (profile == PROFILE_LINEAR) {
+        value = density * (1.0f - a * s);
+    } else if (profile == PROFILE_GAUSSIAN) {
+        value = density * exp(-s * s / (2.0f * a * a));
+    } else if (profile == PROFILE_CORE_SHELL) {
+        value = s <= a ? density : b;
+    } else if (profile == PROFILE_HALO) {
+        float rim = (1.0f - s) / a;
+        value = density * (1.0f + b * exp(-rim * rim));
+    }
+    return clamp(value, 0.0f, max_density);
+}
+
+// Integer hash of a lattice point, the same as in texture.rs
+uint texture_hash(int x, int y, int z, uint seed) {
+    uint h = seed ^ ((uint) x * 73856093u) ^ ((uint) y * 19349663u) ^ ((uint) z * 83492791u);
+    h ^= h >> 16;
+    h *= 0x7feb352du;
+    h ^= h >> 15;
+    h *= 0x846ca68bu;
+    h ^= h >> 16;
+    return h;
+}
+
+// Dot product with one of the 12 edge directions of a cube, picked by the hash
+float texture_gradient(uint h, float x, float y, float z) {
+    uint k = h & 15;
+    float u = k < 8 ? x : y;
+    float v = k < 4 ? y : (k == 12 || k == 14 ? x : z);
+    return ((k & 1) ? -u : u) + ((k & 2) ? -v : v);
+}
+
+float texture_fade(float t) {
+    return t * t * t * (t * (t * 6.0f - 15.0f) + 10.0f);
+}
+
+float perlin_noise(float3 p, uint seed) {
+    float3 f = floor(p);
+    int x = (int) f.x;
+    int y = (int) f.y;
+    int z = (int) f.z;
+    float3 d = p - f;
+    float3 w = { texture_fade(d.x), texture_fade(d.y), texture_fade(d.z) };
+
+    float c000 = texture_gradient(texture_hash(x, y, z, seed), d.x, d.y, d.z);
+    float c100 = texture_gradient(texture_hash(x + 1, y, z, seed), d.x - 1.0f, d.y, d.z);
+    float c010 = texture_gradient(texture_hash(x, y + 1, z, seed), d.x, d.y - 1.0f, d.z);
+    float c110 = texture_gradient(texture_hash(x + 1, y + 1, z, seed), d.x - 1.0f, d.y - 1.0f, d.z);
+    float c001 = texture_gradient(texture_hash(x, y, z + 1, seed), d.x, d.y, d.z - 1.0f);
+    float c101 = texture_gradient(texture_hash(x + 1, y, z + 1, seed), d.x - 1.0f, d.y, d.z - 1.0f);
+    float c011 = texture_gradient(texture_hash(x, y + 1, z + 1, seed), d.x, d.y - 1.0f, d.z - 1.0f);
+    float c111 = texture_gradient(texture_hash(x + 1, y + 1, z + 1, seed), d.x - 1.0f, d.y - 1.0f, d.z - 1.0f);
+
+    float low = mix(mix(c000, c100, w.x), mix(c010, c110, w.x), w.y);
+    float high = mix(mix(c001, c101, w.x), mix(c011, c111, w.x), w.y);
+    return mix(low, high, w.z);
+}
+
+float simplex_noise(float3 p, uint seed) {
+    const float F3 = 1.0f / 3.0f;
+    const float G3 = 1.0f / 6.0f;
+
+    // Skew into the simplex grid, find the cell and the order of the offsets to walk its corners
+    float s = (p.x + p.y + p.z) * F3;
+    float3 cell = floor(p + s);
+    float t = (cell.x + cell.y + cell.z) * G3;
+    float3 x0 = p - (cell - t);
+    int3 first;
+    int3 second;
+    if (x0.x >= x0.y) {
+        if (x0.y >= x0.z) { first = (int3)(1, 0, 0); second = (int3)(1, 1, 0); }
+        else if (x0.x >= x0.z) { first = (int3)(1, 0, 0); second = (int3)(1, 0, 1); }
+        else { first = (int3)(0, 0, 1); second = (int3)(1, 0, 1); }
+    } else if (x0.y < x0.z) { first = (int3)(0, 0, 1); second = (int3)(0, 1, 1); }
+    else if (x0.x < x0.z) { first = (int3)(0, 1, 0); second = (int3)(0, 1, 1); }
+    else { first = (int3)(0, 1, 0); second = (int3)(1, 1, 0); }
+
+    int3 base = { (int) cell.x, (int) cell.y, (int) cell.z };
+    int3 offsets[4] = { (int3)(0, 0, 0), first, second, (int3)(1, 1, 1) };
+    float sum = 0.0f;
+    for (int n = 0; n < 4; n++) {
+        float3 d = x0 - convert_float3(offsets[n]) + n * G3;
+        float falloff = 0.6f - dot(d, d);
+        if (falloff > 0.0f) {
+            int3 c = base + offsets[n];
+            float f2 = falloff * falloff;
+            sum += f2 * f2 * texture_gradient(texture_hash(c.x, c.y, c.z, seed), d.x, d.y, d.z);
+        }
+    }
+    return 32.0f * sum;
+}
+
+// Fractal sum of octaves of the texture, scaled to its amplitude, the same as Texture::value
+float texture_value(global float* texture, float3 p, uint seed) {
+    int kind = (int) texture[0];
+    int octaves = (int) texture[2];
+    float sum = 0.0f;
+    float weight = 1.0f;
+    float total = 0.0f;
+    float frequency = 1.0f / texture[1];
+    for (int octave = 0; octave < octaves; octave++) {
+        float3 q = p * frequency;
+        uint octave_seed = seed + (uint) octave;
+        sum += weight * (kind == TEXTURE_SIMPLEX ? simplex_noise(q, octave_seed) : perlin_noise(q, octave_seed));
+        total += weight;
+        weight *= 0.5f;
+        frequency *= 2.0f;
+    }
+    return texture[3] * sum / total;
+}
+
+// Density with the instance texture for instance ids, or the background texture, stored second, for id 0
+float textured_density(global float* textures, int id, float density, float3 p, uint seed, float max_density) {
+    global float* texture = id != 0 ? textures : textures + TEXTURE_STRIDE;
+    if ((int) texture[0] == TEXTURE_NONE) {
+        return density;
+    }
+    return clamp(density + texture_value(texture, p, seed + (uint) id * 1013u), 0.0f, max_density);
+}
+
+// Label and density of the solid instances at the point, returns how many instances contain it
+uint resolve_solid(global float* instances, global float* nodes, int instance_count, float3 p,
+    int overlap_mode, int count_overlaps, float max_density, int* id, float* density) {
+    uint solid_count = 0;
+    float density_sum = 0.0;
+    *id = 0;
+    *density = 0;
+
+    for (int in = 0; in < instance_count; in++) {
+        global float* instance = instances + in * INSTANCE_STRIDE;
+        if (instance[4] > 0.0 || !is_point_in_box(instance, p)) {
+            continue;
+        }
+
+        int start = (int) instance[2];
+        int count = (int) instance[3];
+        float d = signed_distance(nodes, start, count, p);
+        if (d > 0.0) {
+            continue;
+        }
+
+        // The first instance in the list labels the voxel, unless the densest one wins
+        float instance_density = profile_density(instance, d, max_density);
+        if (*id == 0 || (overlap_mode == OVERLAP_MAX_DENSITY && instance_density > *density)) {
+            *id = (int) instance[0];
+            *density = instance_density;
+        }
+        solid_count++;
+        density_sum += instance_density;
+        if (overlap_mode == OVERLAP_FIRST && !count_overlaps) {
+            break;
+        }
+    }
+    if (overlap_mode == OVERLAP_ADD) {
+        *density = min(density_sum, max_density);
+    } else if (overlap_mode == OVERLAP_AVERAGE && solid_count > 0) {
+        *density = density_sum / solid_count;
+    }
+    return solid_count;
+}
+
+// The background fills the inside of the container, stored after the instances, and air the outside
+bool is_air(global float* instances, global float* nodes, int instance_count, int has_interior, float3 p) {
+    if (!has_interior) {
+        return false;
+    }
+    global float* interior = instances + instance_count * INSTANCE_STRIDE;
+    return signed_distance(nodes, (int) interior[2], (int) interior[3], p) > 0.0;
 }
 
 kernel void main(
     uint empty_space,
-    uint quality,
-    int sphere_count,
+    int instance_count,
+    int has_interior,
+    float spacing_x,
+    float spacing_y,
+    float spacing_z,
+    int overlap_mode,
+    int count_overlaps,
+    int supersampling,
+    uint texture_seed,
+    float max_density,
     global int* dims,
-    global float* spheres,
-    global uint* densities,
-    global int* materials
+    global float* instances,
+    global float* nodes,
+    global float* textures,
+    global float* densities,
+    global int* materials,
+    global uint* overlaps,
+    global float* occupancy
     ) {
         const size_t index = get_global_id(0);
 
-        int rand_seed = index;
-
         float i = (float)(index % dims[0]);
         float j = (float)((index / dims[0]) % dims[1]);
         float k = (float)(index / (dims[0] * dims[1]));
 
+        float3 spacing = { spacing_x, spacing_y, spacing_z };
         float3 p = {
-            (1.0 / dims[0]) * (i + 0.5),
-            (1.0 / dims[1]) * (j + 0.5),
-            (1.0 / dims[2]) * (k + 0.5)
+            spacing_x * (i + 0.5),
+            spacing_y * (j + 0.5),
+            spacing_z * (k + 0.5)
         };
 
-        int current_id = 0;
-        uint current_density = 0;
+        float voxel_size = (spacing_x + spacing_y + spacing_z) / 3.0;
 
-        for (uint in = 0; in < sphere_count; in += 6) {
-            float3 o = {
-                spheres[in],
-                spheres[in+1],
-                spheres[in+2]
-            };
-            float radius = spheres[in+3];
-            int id = (int) spheres[in+4];
-            uint density = (uint) spheres[in+5];
-
-            if (is_point_in_sphere(o, p, radius)) {
-                current_id = id;
-                current_density = density;
-                break;
+        // Thin instances are mixed with whatever is below them by their coverage
+        int thin_id = 0;
+        float thin_density = 0.0;
+        float thin_coverage = 0.0;
+        float thin_threshold = 0.0;
+        uint overlap_count = 0;
+
+        for (int in = 0; in < instance_count; in++) {
+            global float* instance = instances + in * INSTANCE_STRIDE;
+            float thickness = instance[4];
+            if (thickness <= 0.0 || !is_point_in_box(instance, p)) {
+                continue;
+            }
+
+            float d = signed_distance(nodes, (int) instance[2], (int) instance[3], p);
+            float coverage = slab_coverage(d, thickness, voxel_size);
+            if (coverage >= 0.5 * min(thickness, voxel_size) / voxel_size) {
+                overlap_count++;
+            }
+            if (coverage > thin_coverage) {
+                thin_id = (int) instance[0];
+                thin_density = instance[1];
+                thin_coverage = coverage;
+                thin_threshold = 0.5 * min(thickness, voxel_size) / voxel_size;
             }
         }
 
-        if (current_id == 0) {
-            float mu = (float) empty_space / 2.0;
-            current_density = (uint) normal_rand(mu, mu, &rand_seed);
+        // The label is taken at the voxel center
+        int current_id;
+        float current_density;
+        overlap_count += resolve_solid(instances, nodes, instance_count, p, overlap_mode, count_overlaps, max_density, &current_id, &current_density);
+        bool air = current_id == 0 && is_air(instances, nodes, instance_count, has_interior, p);
+
+        // The clean density, noise is added afterwards, see noise.rs
+        float mu;
+        if (air) {
+            mu = 0.0;
+        } else if (current_id == 0) {
+            mu = textured_density(textures, 0, (float) empty_space / 2.0, p, texture_seed, max_density);
         } else {
-            float mu = (float) current_density;
-            float phi = (float) quality;
-            current_density = (uint) normal_rand(mu, phi, &rand_seed);
+            mu = textured_density(textures, current_id, current_density, p, texture_seed, max_density);
         }
+        float filled = current_id != 0 ? 1.0 : 0.0;
 
-        densities[index] = current_density;
+        // With supersampling, the density is the mean over a regular grid of points in the voxel
+        if (supersampling > 1) {
+            float mu_sum = 0.0;
+            int filled_count = 0;
+            for (int sz = 0; sz < supersampling; sz++) {
+                for (int sy = 0; sy < supersampling; sy++) {
+                    for (int sx = 0; sx < supersampling; sx++) {
+                        float3 offset = { sx + 0.5f, sy + 0.5f, sz + 0.5f };
+                        float3 q = p + spacing * (offset / supersampling - 0.5f);
+                        int id;
+                        float density;
+                        resolve_solid(instances, nodes, instance_count, q, overlap_mode, 0, max_density, &id, &density);
+                        if (id != 0) {
+                            mu_sum += textured_density(textures, id, density, q, texture_seed, max_density);
+                            filled_count++;
+                        } else if (!is_air(instances, nodes, instance_count, has_interior, q)) {
+                            mu_sum += textured_density(textures, 0, (float) empty_space / 2.0, q, texture_seed, max_density);
+                        }
+                    }
+                }
+            }
+            int samples = supersampling * supersampling * supersampling;
+            mu = mu_sum / samples;
+            filled = (float) filled_count / samples;
+        }
+
+        if (thin_coverage > 0.0) {
+            mu = thin_coverage * thin_density + (1.0 - thin_coverage) * mu;
+            filled = thin_coverage + (1.0 - thin_coverage) * filled;
+            if (thin_coverage >= thin_threshold) {
+                current_id = thin_id;
+            }
+        }
+        densities[index] = mu;
         materials[index] = current_id;
+        overlaps[index] = overlap_count;
+        occupancy[index] = filled;
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+use rand::Rng;
+
+use crate::{GeneratorData, csg::Transform, sphere::Sphere, vector3float::Vector3Float, container::Container, radius::RadiusDistribution};
+
+pub const LATTICE_NAMES: [&str; 4] = ["sc", "bcc", "fcc", "hcp"];
+
+/// Position of a sphere in the lattice it was generated from.
+#[derive(Clone)]
+pub struct LatticeSite {
+    pub index: [i32; 3],
+    pub basis: usize,
+    pub substituted: bool
+}
+
+/// Cell vectors and basis positions (in cell coordinates) of a lattice with the given spacing.
+fn cell(name: &str, a: f32) -> ([Vector3Float; 3], Vec<[f32; 3]>) {
+    match name {
+        "hcp" => {
+            let c = a * (8.0f32 / 3.0).sqrt();
+            let vectors = [
+                Vector3Float::new(a, 0.0, 0.0),
+                Vector3Float::new(a / 2.0, a * 3.0f32.sqrt() / 2.0, 0.0),
+                Vector3Float::new(0.0, 0.0, c)
+            ];
+            return (vectors, vec![[0.0, 0.0, 0.0], [1.0 / 3.0, 1.0 / 3.0, 0.5]]);
+        },
+        _ => {
+            let vectors = [
+                Vector3Float::new(a, 0.0, 0.0),
+                Vector3Float::new(0.0, a, 0.0),
+                Vector3Float::new(0.0, 0.0, a)
+            ];
+            let basis = match name {
+                "bcc" => vec![[0.0, 0.0, 0.0], [0.5, 0.5, 0.5]],
+                "fcc" => vec![[0.0, 0.0, 0.0], [0.5, 0.5, 0.0], [0.5, 0.0, 0.5], [0.0, 0.5, 0.5]],
+                _ => vec![[0.0, 0.0, 0.0]]
+            };
+            return (vectors, basis);
+        }
+    }
+}
+
+/// Fills the volume with spheres on a lattice rotated around the volume centre. Sites are jittered,
+/// left empty at the vacancy rate, and get the substitute density at the substitution rate.
+pub fn generate_lattice(gen_data: &GeneratorData, radii: &RadiusDistribution, densities: &Vec<u32>, report: &mut Vec<String>) -> Vec<Sphere> {
+    let mut rand = rand::thread_rng();
+    let name = match &gen_data.lattice {
+        Some(l) => l.as_str(),
+        None => "sc"
+    };
+    let a = gen_data.lattice_spacing;
+    let (vectors, basis) = cell(name, a);
+    let e = &gen_data.extent;
+    let transform = Transform::with_euler_angles(Vector3Float::new(e.x / 2.0, e.y / 2.0, e.z / 2.0), &gen_data.lattice_angles);
+    let inside = gen_data.boundary == "inside";
+    let container = Container::new(gen_data);
+
+    let host_density = densities[0];
+    let substitute_density = match gen_data.substitute_density {
+        Some(d) => d,
+        None => densities[1 % densities.len()]
+    };
+
+    // Every rotation of the volume fits in a ball of half its diagonal around the centre,
+    // the hexagonal cell is skewed so its index range is widened
+    let half_diagonal = Vector3Float::new(e.x / 2.0, e.y / 2.0, e.z / 2.0).length();
+    let n = ((half_diagonal / a).ceil() as i32 + 1) * if name == "hcp" { 2 } else { 1 };
+
+    let mut spheres = Vec::new();
+    let mut sites = 0;
+    let mut vacancies = 0;
+    let mut substitutions = 0;
+    for k in -n..=n {
+        for j in -n..=n {
+            for i in -n..=n {
+                for (b, offset) in basis.iter().enumerate() {
+                    let (u, v, w) = (i as f32 + offset[0], j as f32 + offset[1], k as f32 + offset[2]);
+                    let local = Vector3Float::new(
+                        u * vectors[0].x + v * vectors[1].x + w * vectors[2].x,
+                        u * vectors[0].y + v * vectors[1].y + w * vectors[2].y,
+                        u * vectors[0].z + v * vectors[1].z + w * vectors[2].z
+                    );
+                    let site = transform.to_world(&local);
+                    if site.x < 0.0 || site.y < 0.0 || site.z < 0.0 || site.x >= e.x || site.y >= e.y || site.z >= e.z {
+                        continue;
+                    }
+
+                    let r = radii.sample();
+                    let jitter = gen_data.lattice_jitter * a;
+                    let origin = Vector3Float::new(
+                        site.x + jitter * (rand.gen::<f32>() - 0.5),
+                        site.y + jitter * (rand.gen::<f32>() - 0.5),
+                        site.z + jitter * (rand.gen::<f32>() - 0.5)
+                    );
+                    let fits = |v: f32, size: f32| v >= r.min(size / 2.0) && v <= size - r.min(size / 2.0);
+                    if inside && !(fits(origin.x, e.x) && fits(origin.y, e.y) && fits(origin.z, e.z)) {
+                        continue;
+                    }
+                    if let Some(container) = &container {
+                        if !container.fits(&origin, r) {
+                            continue;
+                        }
+                    }
+
+                    sites += 1;
+                    if rand.gen::<f32>() < gen_data.vacancy_rate {
+                        vacancies += 1;
+                        continue;
+                    }
+                    let substituted = rand.gen::<f32>() < gen_data.substitution_rate;
+                    if substituted {
+                        substitutions += 1;
+                    }
+
+                    let density = if substituted { substitute_density } else { host_density };
+                    let id = spheres.len() as u32 + 1;
+                    let lattice_site = LatticeSite {
+                        index: [i, j, k],
+                        basis: b,
+                        substituted
+                    };
+                    spheres.push(Sphere::new(id, r, origin, density).with_lattice_site(lattice_site));
+                }
+            }
+        }
+    }
+    spheres.sort();
+
+    report.push(format!("Lattice sites: {}\nVacancies: {}\nSubstitutions: {}", sites, vacancies, substitutions));
+    println!("Generated {} spheres on a {} lattice", spheres.len(), name);
+    return spheres;
+}
@@ -1,7 +1,29 @@
 mod vector3uint;
 mod vector3float;
 mod sphere;
-//mod cpu;
+mod csg;
+mod instance;
+mod scene;
+mod phantom;
+mod pattern;
+mod thin;
+mod packing;
+mod placement;
+mod intensity;
+mod lattice;
+mod settling;
+mod container;
+mod radius;
+mod orientation;
+mod overlap;
+mod profile;
+mod texture;
+mod noise;
+mod range;
+mod psf;
+mod bias;
+mod import;
+mod cpu;
 mod gpu;
 
 use std::{fs::File, io::{Write, Error, BufWriter}, time::Instant, f32::consts::PI};
@@ -9,9 +31,17 @@ use std::{fs::File, io::{Write, Error, BufWriter}, time::Instant, f32::consts::P
 use byteorder::{WriteBytesExt, BigEndian};
 use rand::seq::SliceRandom;
 use sphere::Sphere;
+use instance::Instance;
 use vector3uint::Vector3Usize;
+use vector3float::Vector3Float;
 use std::env;
 
+/// Largest number of supersampling points per axis, the cost grows with its cube.
+const MAX_SUPERSAMPLING: u32 = 8;
+
+/// Sample types of the density volume, all but u8 are written big-endian.
+const DATA_TYPES: [&str; 4] = ["u8", "u16", "i16", "f32"];
+
 pub struct GeneratorData {
     pub variation_count: u32,
     pub sphere_count: u32,
@@ -20,45 +50,213 @@ pub struct GeneratorData {
     pub empty_space: u32,
     pub noise_span: u32,
     pub pixel_dimensions: Vector3Usize,
-    pub generation_name: String
+    pub extent: Vector3Float,
+    pub unit: String,
+    pub generation_name: String,
+    pub scene_file: Option<String>,
+    pub instance_file: Option<String>,
+    pub phantom: Option<String>,
+    pub pattern: Option<String>,
+    pub min_gap: Option<f32>,
+    pub placement_attempts: u32,
+    pub volume_fraction: Option<f32>,
+    pub packing: String,
+    pub boundary: String,
+    pub process: String,
+    pub cluster_count: u32,
+    pub cluster_spread: f32,
+    pub interaction_radius: f32,
+    pub interaction_strength: f32,
+    pub lattice_jitter: f32,
+    pub intensity: Option<String>,
+    pub intensity_mask: Option<String>,
+    pub lattice: Option<String>,
+    pub lattice_spacing: f32,
+    pub lattice_angles: Vector3Float,
+    pub vacancy_rate: f32,
+    pub substitution_rate: f32,
+    pub substitute_density: Option<u32>,
+    pub settle: bool,
+    pub container: Option<String>,
+    pub container_wall: f32,
+    pub container_density: u32,
+    pub radius_distribution: String,
+    pub radius_parameters: Vec<f32>,
+    pub radius_histogram: Option<String>,
+    pub orientation: String,
+    pub orientation_parameters: Vec<f32>,
+    pub orientation_axis: Vector3Float,
+    pub overlap: Option<String>,
+    pub overlap_map: bool,
+    pub supersampling: u32,
+    pub profile: String,
+    pub profile_ranges: Vec<f32>,
+    pub texture: Option<texture::Texture>,
+    pub background_texture: Option<texture::Texture>,
+    pub noise: String,
+    pub noise_parameters: Vec<f32>,
+    pub range: (f32, f32),
+    pub range_policy: String,
+    pub data_type: String,
+    pub psf: Option<String>,
+    pub psf_parameters: Vec<f32>,
+    pub psf_file: Option<String>,
+    pub bias: Option<String>,
+    pub bias_parameters: Vec<f32>,
+    pub soft_labels: bool,
+    pub disc_count: u32,
+    pub sheet_count: u32,
+    pub min_thin_thickness: f32,
+    pub max_thin_thickness: f32,
+    pub min_thin_radius: f32,
+    pub max_thin_radius: f32,
+    pub thin_density: Option<u32>,
+    pub use_cpu: bool
 }
 
-fn generate_spheres(gen_data: &GeneratorData) -> Vec<Sphere> {
-    let sphere_count = gen_data.sphere_count;
-    let min_rad = gen_data.min_sphere_radius;
-    let max_rad = gen_data.max_sphere_radius;
+impl GeneratorData {
+    /// Size of a voxel along each axis, in scene units.
+    pub fn spacing(&self) -> Vector3Float {
+        let dims = &self.pixel_dimensions;
+        return Vector3Float::new(self.extent.x / dims.x as f32, self.extent.y / dims.y as f32, self.extent.z / dims.z as f32);
+    }
+
+    /// Smallest and largest density the output type can store. Floats use the same scale as 8 bit volumes,
+    /// with 255 as the largest density and no lower limit. The default output range still starts at 0,
+    /// so negative noise is only kept when --range sets a negative lower bound.
+    pub fn density_limits(&self) -> (f32, f32) {
+        match self.data_type.as_str() {
+            "u16" => (0.0, u16::MAX as f32),
+            "i16" => (i16::MIN as f32, i16::MAX as f32),
+            "f32" => (f32::MIN, 255.0),
+            _ => (0.0, u8::MAX as f32)
+        }
+    }
+
+    /// Largest density of instances, profiles and textures.
+    pub fn max_density(&self) -> f32 {
+        return self.density_limits().1;
+    }
+
+    /// Mean size of a voxel, in scene units.
+    pub fn voxel_size(&self) -> f32 {
+        let spacing = self.spacing();
+        return (spacing.x + spacing.y + spacing.z) / 3.0;
+    }
+}
 
+/// Volumes rendered by the CPU or OpenCL backend, in the same voxel order.
+pub struct VolumeTextures {
+    pub materials: Vec<u32>,
+    /// Densities before noise is added
+    pub densities: Vec<f32>,
+    /// Number of instances covering each voxel
+    pub overlaps: Vec<u32>,
+    /// Fraction of each voxel filled by instances
+    pub occupancy: Vec<f32>
+}
+
+fn generate_spheres(gen_data: &GeneratorData, report: &mut Vec<String>) -> Result<Vec<Sphere>, Error> {
+    let sphere_count = gen_data.sphere_count;
+    let radii = match radius::RadiusDistribution::new(gen_data) {
+        Ok(r) => r,
+        Err(e) => {
+            return Err(e);
+        }
+    };
 
     let mut spheres = Vec::new();
 
     let mut rand_rng = rand::thread_rng();
-    let mut densities: Vec<u32> = (gen_data.empty_space+gen_data.noise_span..=255).collect();
+    let mut densities: Vec<u32> = (gen_data.empty_space+gen_data.noise_span..=gen_data.max_density() as u32).collect();
     densities.shuffle(&mut rand_rng);
 
+    if gen_data.lattice.is_some() {
+        return Ok(lattice::generate_lattice(gen_data, &radii, &densities, report));
+    }
+
+    if gen_data.settle {
+        return Ok(settling::settle_spheres(gen_data, &radii, &densities, report));
+    }
+
+    if let Some(target) = gen_data.volume_fraction {
+        return Ok(packing::pack_spheres(gen_data, &radii, target, &densities, report));
+    }
+
+    let inside = gen_data.boundary == "inside";
+    let periodic = if gen_data.boundary == "periodic" { Some(&gen_data.extent) } else { None };
+    let process = match placement::PointProcess::new(gen_data) {
+        Ok(p) => p,
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
     let density_size = densities.len();
     for i in 1..sphere_count+1 {
         let density = densities[(i-1) as usize % density_size];
-        let sphere = Sphere::generate_sphere(min_rad, max_rad, density, i);
-        spheres.push(sphere);
+
+        let sphere = match gen_data.min_gap {
+            Some(gap) => {
+                // Rejection sampling, the sphere is dropped if it does not fit in the given attempts
+                let id = spheres.len() as u32 + 1;
+                let mut placed = None;
+                for _ in 0..gen_data.placement_attempts {
+                    let candidate = Sphere::generate_sphere(&radii, density, id, &process, &spheres, inside);
+                    if !spheres.iter().any(|s: &Sphere| s.overlaps(&candidate, gap, periodic)) {
+                        placed = Some(candidate);
+                        break;
+                    }
+                }
+                placed
+            },
+            None => Some(Sphere::generate_sphere(&radii, density, i, &process, &spheres, inside))
+        };
+
+        if let Some(sphere) = sphere {
+            spheres.push(sphere);
+        }
     }
     spheres.sort();
 
+    let fallbacks = process.fallback_count();
+    if fallbacks > 0 {
+        println!("Warning: No candidate was accepted by the placement process in {} tries for {} origins, they were placed by the fallback without its constraints", placement::MAX_TRIES, fallbacks);
+        report.push(format!("Origins placed by the fallback without the placement constraints: {}", fallbacks));
+    }
+    if spheres.len() < sphere_count as usize {
+        println!("Warning: Only {} of {} spheres could be placed without overlapping", spheres.len(), sphere_count);
+    }
+    if gen_data.min_gap.is_some() {
+        report.push(format!("Placed spheres: {} of {}", spheres.len(), sphere_count));
+    }
+    let crossing = spheres.iter().filter(|s| !s.is_inside_volume(&gen_data.extent)).count();
+    report.push(format!("Spheres crossing the volume faces: {}", crossing));
+
     println!("Generated {} spheres", spheres.len());
-    return spheres;
+    return Ok(spheres);
 }
 
-fn write_raw(filename: &str, texture: Vec<u32>) -> Result<(), Error> {
+/// Writes the densities as samples of the data type, they are expected to be inside its range already.
+fn write_raw(filename: &str, texture: Vec<f32>, data_type: &str) -> Result<(), Error> {
     println!("Writing density texture...");
     let time = Instant::now();
 
-    let texture_u8: Vec<u8> = texture.into_iter().map(|x| x as u8).collect();
-    
     match File::create(filename) {
-        Ok(mut f) => {
-            match f.write_all(&texture_u8) {
-                Ok(_) => (),
-                Err(e) => {
-                    return Err(e);
+        Ok(f) => {
+            let mut buf = BufWriter::new(f);
+            for t in texture {
+                let written = match data_type {
+                    "u16" => buf.write_u16::<BigEndian>(t.round() as u16),
+                    "i16" => buf.write_i16::<BigEndian>(t.round() as i16),
+                    "f32" => buf.write_f32::<BigEndian>(t),
+                    _ => buf.write_u8(t.round() as u8)
+                };
+                match written {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Err(e);
+                    }
                 }
             }
         },
@@ -96,12 +294,106 @@ fn write_segmentation(filename: &str, texture: Vec<u32>) -> Result<(), Error> {
     return Ok(());
 }
 
-fn write_execution_data(filename: &str, data: &GeneratorData) -> Result<(), Error> {
+fn write_float_texture(filename: &str, texture: Vec<f32>, name: &str) -> Result<(), Error> {
+    println!("Writing {} texture...", name);
+    let time = Instant::now();
+
+    match File::create(filename) {
+        Ok(f) => {
+            let mut buf = BufWriter::new(f);
+            for t in texture {
+                match buf.write_f32::<BigEndian>(t) {
+                    Ok(_) => (),
+                    Err(e) => {
+                        return Err(e);
+                    }
+                }
+            }
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    println!("Written {} texture to file ({} secs)", name, time.elapsed().as_secs_f32());
+    return Ok(());
+}
+
+fn write_execution_data(filename: &str, data: &GeneratorData, report: &Vec<String>) -> Result<(), Error> {
+    let mut info = format!("Name: {}\nVariation count: {}\nSphere count: {}\nSphere range: ({}, {})\nEmpty space density: {}\nNoise range: {}\nVolume dimensions: {}x{}x{}",
+        data.generation_name, data.variation_count, data.sphere_count, data.min_sphere_radius, data.max_sphere_radius,
+        data.empty_space, data.noise_span, data.pixel_dimensions.x, data.pixel_dimensions.y, data.pixel_dimensions.z);
+    let (spacing, e) = (data.spacing(), &data.extent);
+    let unit = if data.unit.is_empty() { String::from("(relative to the largest extent)") } else { data.unit.clone() };
+    info.push_str(&format!("\nVoxel spacing: ({}, {}, {}) {}\nPhysical extent: ({}, {}, {}) {}",
+        spacing.x, spacing.y, spacing.z, unit, e.x, e.y, e.z, unit));
+    if let Some(scene) = &data.scene_file {
+        info.push_str(&format!("\nScene file: {}", scene));
+    }
+    if let Some(instance_file) = &data.instance_file {
+        info.push_str(&format!("\nInstance data: {}", instance_file));
+    }
+    if let Some(phantom) = &data.phantom {
+        info.push_str(&format!("\nPhantom: {}", phantom));
+    }
+    if let Some(pattern) = &data.pattern {
+        info.push_str(&format!("\nPattern: {} ({})", pattern, pattern::describe_pattern(pattern)));
+    }
+    if data.disc_count + data.sheet_count > 0 {
+        let density = match data.thin_density {
+            Some(d) => d.to_string(),
+            None => String::from("random")
+        };
+        info.push_str(&format!("\nThin features: {} discs, {} sheets\nThin feature thickness (voxels): ({}, {})\nThin feature radius: ({}, {})\nThin feature density: {}",
+            data.disc_count, data.sheet_count, data.min_thin_thickness, data.max_thin_thickness,
+            data.min_thin_radius, data.max_thin_radius, density));
+        info.push_str(&format!("\nThin feature orientation: {}", orientation::OrientationDistribution::describe(data)));
+    }
+    if data.scene_file.is_none() && data.instance_file.is_none() && data.phantom.is_none() && data.pattern.is_none() {
+        info.push_str(&format!("\nRadius distribution: {}", radius::RadiusDistribution::describe(data)));
+        info.push_str(&format!("\nSphere profile: {}", profile::Profile::describe(data)));
+    }
+    if let Some(gap) = data.min_gap {
+        info.push_str(&format!("\nMinimum gap between spheres: {}\nPlacement attempts: {}", gap, data.placement_attempts));
+    }
+    info.push_str(&format!("\nBoundary: {}", data.boundary));
+    info.push_str(&format!("\nOverlap policy: {}", overlap::describe(data.overlap.as_ref())));
+    info.push_str(&format!("\nSupersampling: {}x{}x{} points per voxel", data.supersampling, data.supersampling, data.supersampling));
+    info.push_str(&format!("\nPoint spread function: {}", psf::describe(data)));
+    info.push_str(&format!("\nBias field: {}", bias::describe(data)));
+    info.push_str(&format!("\nNoise model: {}", noise::describe(data)));
+    info.push_str(&format!("\nData type: {}{}", data.data_type, if data.data_type == "u8" { "" } else { " (big-endian)" }));
+    info.push_str(&format!("\nOutput range: {}", range::describe(data)));
+    info.push_str(&format!("\nInstance texture: {}\nBackground texture: {}", texture::Texture::describe(data.texture.as_ref()),
+        texture::Texture::describe(data.background_texture.as_ref())));
+    if let Some(lattice) = &data.lattice {
+        let a = &data.lattice_angles;
+        info.push_str(&format!("\nLattice: {}\nLattice spacing: {}\nLattice angles: ({}, {}, {})\nLattice jitter: {}\nVacancy rate: {}\nSubstitution rate: {}",
+            lattice, data.lattice_spacing, a.x, a.y, a.z, data.lattice_jitter, data.vacancy_rate, data.substitution_rate));
+    } else if data.settle {
+        info.push_str("\nPlacement: settled under gravity along -z");
+    } else {
+        info.push_str(&format!("\nPlacement process: {}", placement::PointProcess::describe(data)));
+    }
+    if let Some(fraction) = data.volume_fraction {
+        info.push_str(&format!("\nTarget volume fraction: {}\nPacking algorithm: {}", fraction, data.packing));
+    }
+    if let Some(container) = &data.container {
+        info.push_str(&format!("\nContainer: {}\nContainer wall thickness: {}\nContainer density: {}", container, data.container_wall, data.container_density));
+    }
+    info.push_str(&format!("\nBackend: {}", if data.use_cpu { "CPU" } else { "OpenCL" }));
+    for line in report {
+        info.push_str(&format!("\n{}", line));
+    }
+
     match File::create(filename) {
         Ok(mut f) => {
-            write!(f, "Name: {}\nVariation count: {}\nSphere count: {}\nSphere range: ({}, {})\nEmpty space density: {}\nNoise range: {}\nVolume dimensions: {}x{}x{}",
-                data.generation_name, data.variation_count, data.sphere_count, data.min_sphere_radius, data.max_sphere_radius,
-                data.empty_space, data.noise_span, data.pixel_dimensions.x, data.pixel_dimensions.y, data.pixel_dimensions.z);
+            match f.write_all(info.as_bytes()) {
+                Ok(_) => (),
+                Err(e) => {
+                    return Err(e);
+                }
+            }
         },
         Err(e) => {
             return Err(e);
@@ -115,7 +407,11 @@ fn write_execution_data(filename: &str, data: &GeneratorData) -> Result<(), Erro
 fn write_sphere_data(filename: &str, spheres: &Vec<Sphere>) -> Result<(), Error> {    
     match File::create(filename) {
         Ok(mut f) => {
-            write!(f, "ID,POSITION X,POSITION Y,POSITION Z,RADIUS,DENSITY,SURFACE AREA,VOLUME\n");
+            let on_lattice = spheres.iter().any(|s| s.lattice_site().is_some());
+            let lattice_header = if on_lattice { ",LATTICE I,LATTICE J,LATTICE K,BASIS,SUBSTITUTED" } else { "" };
+            let profiled = spheres.iter().any(|s| s.profile().is_some());
+            let profile_header = if profiled { ",PROFILE,PROFILE A,PROFILE B" } else { "" };
+            write!(f, "ID,POSITION X,POSITION Y,POSITION Z,RADIUS,DENSITY,SURFACE AREA,VOLUME{}{}\n", lattice_header, profile_header);
             for sphere in spheres {
                 let id = sphere.id();
                 let rad = sphere.radius();
@@ -124,7 +420,13 @@ fn write_sphere_data(filename: &str, spheres: &Vec<Sphere>) -> Result<(), Error>
                 let sur = 4.0 * PI * rad * rad;
                 let vol = sur * rad / 3.0;
                 
-                let line = format!("{},{},{},{},{},{},{},{}", id, pos.x, pos.y, pos.z, rad, den, sur, vol);
+                let mut line = format!("{},{},{},{},{},{},{},{}", id, pos.x, pos.y, pos.z, rad, den, sur, vol);
+                if let Some(site) = sphere.lattice_site() {
+                    line.push_str(&format!(",{},{},{},{},{}", site.index[0], site.index[1], site.index[2], site.basis, site.substituted as u8));
+                }
+                if profiled {
+                    line.push_str(&profile_columns(sphere.profile(), false));
+                }
                 write!(f, "{}\n", line);
             }
         },
@@ -137,14 +439,146 @@ fn write_sphere_data(filename: &str, spheres: &Vec<Sphere>) -> Result<(), Error>
     return Ok(());
 }
 
+/// Profile name and parameters as CSV columns, with the depth for instances that have no radius.
+fn profile_columns(profile: Option<&profile::Profile>, with_depth: bool) -> String {
+    let mut columns = match profile {
+        Some(p) => format!(",{},{},{}", p.kind, p.parameters[0], p.parameters[1]),
+        None => String::from(",flat,0,0")
+    };
+    if with_depth {
+        columns.push_str(&format!(",{}", profile.map(|p| p.depth).unwrap_or(0.0)));
+    }
+    return columns;
+}
+
+fn write_instance_data(filename: &str, instances: &Vec<Instance>) -> Result<(), Error> {
+    // The shape stays the last column
+    let profiled = instances.iter().any(|i| i.profile().is_some());
+    let profile_header = if profiled { "PROFILE,PROFILE A,PROFILE B,PROFILE DEPTH," } else { "" };
+    let mut data = format!("ID,CLASS,DENSITY,THICKNESS,MIN X,MIN Y,MIN Z,MAX X,MAX Y,MAX Z,{}SHAPE\n", profile_header);
+    for instance in instances {
+        let bb = instance.bounding_box();
+        let profile = if profiled { profile_columns(instance.profile(), true) } else { String::new() };
+        data.push_str(&format!("{},{},{},{},{},{},{},{},{},{}{},{}\n", instance.id(), instance.class(), instance.density(),
+            instance.thickness(), bb.0.0, bb.1.0, bb.2.0, bb.0.1, bb.1.1, bb.2.1, profile, instance.shape().to_scene_string()));
+    }
+
+    match File::create(filename) {
+        Ok(mut f) => {
+            match f.write_all(data.as_bytes()) {
+                Ok(_) => (),
+                Err(e) => {
+                    return Err(e);
+                }
+            }
+        },
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    println!("Written instance data");
+    return Ok(());
+}
+
+/// Returns the instances to render, and the spheres they were made from when placing random spheres.
+fn generate_instances(gen_data: &GeneratorData, report: &mut Vec<String>) -> Result<(Vec<Instance>, Option<Vec<Sphere>>), Error> {
+    if let Some(scene_file) = &gen_data.scene_file {
+        match scene::load_scene(scene_file, gen_data.voxel_size()) {
+            Ok(instances) => {
+                return Ok((instances, None));
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+
+    if let Some(instance_file) = &gen_data.instance_file {
+        match import::load_instance_data(instance_file, gen_data.voxel_size()) {
+            Ok(loaded) => {
+                return Ok(loaded);
+            },
+            Err(e) => {
+                return Err(e);
+            }
+        }
+    }
+
+    // Phantom and pattern densities are designed for 8 bit volumes
+    let scale = gen_data.max_density() / 255.0;
+    let scaled = |instances: Vec<Instance>| -> Vec<Instance> {
+        return instances.into_iter().map(|i| {
+            let density = (i.density() as f32 * scale).round() as u32;
+            i.with_density(density)
+        }).collect();
+    };
+
+    if let Some(phantom) = &gen_data.phantom {
+        return Ok((scaled(phantom::generate_phantom(phantom, &gen_data.extent)), None));
+    }
+
+    if let Some(pattern) = &gen_data.pattern {
+        return Ok((scaled(pattern::generate_pattern(pattern, &gen_data.pixel_dimensions, &gen_data.extent)), None));
+    }
+
+    let spheres = match generate_spheres(gen_data, report) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(e);
+        }
+    };
+    // Every sphere gets its own profile parameters, scaled to its radius
+    let spheres: Vec<Sphere> = spheres.into_iter().map(|s| {
+        let profile = profile::Profile::sample(gen_data, s.radius());
+        s.with_profile(profile)
+    }).collect();
+    let instances = spheres.iter().map(|s| s.to_instance()).collect();
+    return Ok((instances, Some(spheres)));
+}
+
 fn generation(generator_data: &GeneratorData, i: u32) -> Result<(), Error> {
     println!("Generating {}. iteration...", i+1);
     let time = Instant::now();
 
     let gen_name = &generator_data.generation_name;
-    let inst_count = generator_data.sphere_count;
     let dims = &generator_data.pixel_dimensions;
 
+    // Values measured on this volume, written to the execution data
+    let mut report = Vec::new();
+
+    let (mut instances, mut spheres) = match generate_instances(&generator_data, &mut report) {
+        Ok(i) => i,
+        Err(e) => {
+            return Err(e);
+        }
+    };
+
+    if generator_data.disc_count + generator_data.sheet_count > 0 {
+        let first_id = instances.iter().map(|i| i.id()).max().unwrap_or(0) + 1;
+        match thin::generate_thin_instances(&generator_data, first_id, &mut report) {
+            Ok(thin) => instances.extend(thin),
+            Err(e) => {
+                return Err(e);
+            }
+        }
+        // Sphere data cannot describe the thin features
+        spheres = None;
+    }
+
+    // The container is labelled after all other instances, and is not wrapped in a periodic volume
+    let container = container::Container::new(&generator_data);
+    let mut wall = None;
+    let mut wall_id = None;
+    if let Some(container) = &container {
+        let id = instances.iter().map(|i| i.id()).max().unwrap_or(0) + 1;
+        report.push(format!("Container label: {}", id));
+        wall = Some(container.wall(id));
+        wall_id = Some(id);
+    }
+    let interior = container.as_ref().map(|c| c.interior());
+    let inst_count = instances.len() + wall.is_some() as usize;
+
     let volume_filename = format!("{}_{}_i{}_{}x{}x{}.raw",
         gen_name, i, inst_count, dims.x, dims.y, dims.z);
     let material_filename = format!("{}_{}_i{}_{}x{}x{}.sgm",
@@ -153,31 +587,119 @@ fn generation(generator_data: &GeneratorData, i: u32) -> Result<(), Error> {
         gen_name, i, inst_count, dims.x, dims.y, dims.z);
     let info_filename = format!("{}_{}_i{}_{}x{}x{}.txt",
         gen_name, i, inst_count, dims.x, dims.y, dims.z);
+    let overlap_filename = format!("{}_{}_i{}_{}x{}x{}_overlaps.raw",
+        gen_name, i, inst_count, dims.x, dims.y, dims.z);
+    let occupancy_filename = format!("{}_{}_i{}_{}x{}x{}_occupancy.raw",
+        gen_name, i, inst_count, dims.x, dims.y, dims.z);
+    let bias_filename = format!("{}_{}_i{}_{}x{}x{}_bias.raw",
+        gen_name, i, inst_count, dims.x, dims.y, dims.z);
+
+    let mut render_instances = if generator_data.boundary == "periodic" {
+        instance::add_periodic_images(instances.clone(), &generator_data.extent)
+    } else {
+        instances.clone()
+    };
+    overlap::order_instances(&mut render_instances, generator_data.overlap.as_ref());
+    if let Some(wall) = wall {
+        render_instances.push(wall.clone());
+        instances.push(wall);
+    }
+
+    let textures = if generator_data.use_cpu {
+        cpu::generate_volume_textures(&generator_data, &render_instances, interior.as_ref())
+    } else {
+        gpu::generate_volume_textures_cl(&generator_data, &render_instances, interior.as_ref())
+    };
+
+    if let Some(target) = generator_data.volume_fraction {
+        // The container wall is not part of the packing
+        let wall_id = wall_id.unwrap_or(0);
+        let filled = textures.materials.iter().filter(|m| **m != 0 && **m != wall_id).count();
+        let e = &generator_data.extent;
+        // With a container, the fraction is of its interior, in voxels of the same size
+        let (space, measured_in) = match &container {
+            Some(c) => (c.interior_volume() / (e.x * e.y * e.z), "container interior"),
+            None => (1.0, "volume")
+        };
+        let fraction = filled as f32 / (textures.materials.len() as f32 * space);
+        println!("Achieved volume fraction {} of the {} (target {})", fraction, measured_in, target);
+        report.push(format!("Achieved volume fraction: {} of the {}", fraction, measured_in));
+    }
+
+    let mut densities = textures.densities;
+    match psf::apply_psf(&generator_data, &mut densities) {
+        Ok(_) => (),
+        Err(e) => {
+            return Err(e);
+        }
+    };
 
-    let spheres = generate_spheres(&generator_data);
-    let (material_tex, volume_tex) = gpu::generate_volume_textures_cl(&generator_data, &spheres);
+    // The bias shades the blurred signal, noise is added on top of it
+    if generator_data.bias.is_some() {
+        let field = bias::generate_bias_field(&generator_data);
+        for (d, b) in densities.iter_mut().zip(field.iter()) {
+            *d *= b;
+        }
+        let (low, high) = field.iter().fold((f32::MAX, f32::MIN), |(l, h), b| (l.min(*b), h.max(*b)));
+        report.push(format!("Bias field range: ({}, {})", low, high));
+        match write_float_texture(&bias_filename, field, "bias") {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(e);
+            }
+        };
+    }
+    noise::apply_noise(&generator_data, &mut densities, &textures.materials);
+    let (below, above) = range::apply_range(&generator_data, &mut densities);
+    if below + above > 0 {
+        println!("Brought {} voxels below and {} above the output range into it", below, above);
+    }
+    report.push(format!("Voxels out of range: {} below, {} above", below, above));
 
-    match write_raw(&volume_filename, volume_tex) {
+    match write_raw(&volume_filename, densities, &generator_data.data_type) {
         Ok(_) => (),
         Err(e) => {
             return Err(e);
         }
     };
-    match write_segmentation(&material_filename, material_tex) {
+    if generator_data.overlap_map {
+        let overlapping = textures.overlaps.iter().filter(|c| **c > 1).count();
+        report.push(format!("Voxels covered by more than one instance: {}", overlapping));
+        // Counts are stored as 8 bits like the densities
+        match write_raw(&overlap_filename, textures.overlaps.into_iter().map(|c| c.min(255) as f32).collect(), "u8") {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(e);
+            }
+        };
+    }
+    match write_segmentation(&material_filename, textures.materials) {
         Ok(_) => (),
         Err(e) => {
             return Err(e);
         }
     };
+    if generator_data.soft_labels {
+        match write_float_texture(&occupancy_filename, textures.occupancy, "occupancy") {
+            Ok(_) => (),
+            Err(e) => {
+                return Err(e);
+            }
+        };
+    }
 
-    match write_sphere_data(&spheres_filename, &spheres) {
+    let instance_data = match &spheres {
+        Some(spheres) => write_sphere_data(&spheres_filename, spheres),
+        None => write_instance_data(&spheres_filename, &instances)
+    };
+    match instance_data {
         Ok(_) => (),
         Err(e) => {
             return Err(e);
         }
     };
 
-    match write_execution_data(&info_filename, &generator_data) {
+    match write_execution_data(&info_filename, &generator_data, &report) {
         Ok(_) => (),
         Err(e) => {
             return Err(e);
@@ -198,8 +720,72 @@ fn get_params_from_args() -> Option<GeneratorData> {
         empty_space: 30,
         noise_span: 10,
         pixel_dimensions: Vector3Usize::new(256, 256, 256),
-        generation_name: String::from("untitled")
+        extent: Vector3Float::new(1.0, 1.0, 1.0),
+        unit: String::new(),
+        generation_name: String::from("untitled"),
+        scene_file: None,
+        instance_file: None,
+        phantom: None,
+        pattern: None,
+        min_gap: None,
+        placement_attempts: 1000,
+        volume_fraction: None,
+        packing: String::from("auto"),
+        boundary: String::from("clip"),
+        process: String::from("poisson"),
+        cluster_count: 10,
+        cluster_spread: 0.05,
+        interaction_radius: 0.1,
+        interaction_strength: 0.2,
+        lattice_jitter: 0.2,
+        intensity: None,
+        intensity_mask: None,
+        lattice: None,
+        lattice_spacing: 0.1,
+        lattice_angles: Vector3Float::new(0.0, 0.0, 0.0),
+        vacancy_rate: 0.0,
+        substitution_rate: 0.0,
+        substitute_density: None,
+        settle: false,
+        container: None,
+        container_wall: 0.02,
+        container_density: 200,
+        radius_distribution: String::from("uniform"),
+        radius_parameters: Vec::new(),
+        radius_histogram: None,
+        orientation: String::from("uniform"),
+        orientation_parameters: Vec::new(),
+        orientation_axis: Vector3Float::new(0.0, 0.0, 1.0),
+        overlap: None,
+        overlap_map: false,
+        supersampling: 1,
+        profile: String::from("flat"),
+        profile_ranges: Vec::new(),
+        texture: None,
+        background_texture: None,
+        noise: String::from("classic"),
+        noise_parameters: Vec::new(),
+        range: (0.0, 255.0),
+        range_policy: String::from("clamp"),
+        data_type: String::from("u8"),
+        psf: None,
+        psf_parameters: Vec::new(),
+        psf_file: None,
+        bias: None,
+        bias_parameters: Vec::new(),
+        soft_labels: false,
+        disc_count: 0,
+        sheet_count: 0,
+        min_thin_thickness: 0.5,
+        max_thin_thickness: 2.0,
+        min_thin_radius: 0.05,
+        max_thin_radius: 0.2,
+        thin_density: None,
+        use_cpu: false
     };
+    let mut extent: Option<Vector3Float> = None;
+    let mut voxel_spacing: Option<Vector3Float> = None;
+    let mut range: Option<(f32, f32)> = None;
 
     for i in 0..args.len() {
         if args[i] == String::from("-o") {
@@ -339,27 +925,740 @@ fn get_params_from_args() -> Option<GeneratorData> {
             }
         }
 
-        else if args[i] == "-h" {
-            println!("-----------------------------------------------------------");
-            println!("This is a small tool for the creation of testing volumes.\n");
-            println!("This tool outputs four files:");
-            println!("  * .raw file with volumetric data as a sequence of unsigned 8-bit integers");
-            println!("  * .sgm file with space segmented into classes as a sequence of unsigned 32-bit integers");
-            println!("  * .txt file containing settings used for data generation");
-            println!("  * .csv file with information about generated spheres (such as radius, position, id...)\n");
-            println!("The supported parameters are:");
-            println!("  * -h  Shows this help message.");
-            println!("  * -o  Output name to append to generated files. Defaults to {}.", gen_data.generation_name);
-            println!("  * -v  Number of variations. Generates and exports this many volumes with given settings, but different hidden parameters (instance positions etc.). Defaults to {}.", gen_data.variation_count);
-            println!("  * -i  Number of instances (spheres) to put inside the volume. Defaults to {}.", gen_data.sphere_count);
-            println!("  * -r  Interval from which to uniformly sample a single sphere radius. Specified as two floats, separated with a whitespace. If only one float is provided, the range is [0, given radius]. Defaults to [{}-{}]", gen_data.min_sphere_radius, gen_data.max_sphere_radius);
-            println!("  * -n  Value in range [0,255], up to which the values are considered empty space. Can be treated as the largest possible density of the empty space. Defaults to {}.", gen_data.empty_space);
-            println!("  * -q  Quality of the volume, given as the amount of density the assigned instance density can differ by. Larger means more noise. Defaults to {}.", gen_data.noise_span);
-            println!("  * -d  Dimensions of the volume, provided as three integers separated by 'x'. Defaults to {}x{}x{}.", gen_data.pixel_dimensions.x, gen_data.pixel_dimensions.y, gen_data.pixel_dimensions.z);
-            println!("-----------------------------------------------------------");
-            return None;
+        else if args[i] == "--scene" {
+            if i+1 < args.len() {
+                gen_data.scene_file = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Scene file not specified, generating spheres instead");
+            }
         }
-    }
+
+        else if args[i] == "--from-csv" {
+            if i+1 < args.len() {
+                gen_data.instance_file = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Instance data file not specified, generating spheres instead");
+            }
+        }
+
+        else if args[i] == "--phantom" {
+            if i+1 < args.len() {
+                if phantom::PHANTOM_NAMES.contains(&args[i+1].as_str()) {
+                    gen_data.phantom = Some(args[i+1].clone());
+                } else {
+                    println!("Warning: Unknown phantom '{}', generating spheres instead", args[i+1]);
+                }
+            } else {
+                println!("Warning: Phantom not specified, generating spheres instead");
+            }
+        }
+
+        else if args[i] == "--pattern" {
+            if i+1 < args.len() {
+                if pattern::PATTERN_NAMES.contains(&args[i+1].as_str()) {
+                    gen_data.pattern = Some(args[i+1].clone());
+                } else {
+                    println!("Warning: Unknown test pattern '{}', generating spheres instead", args[i+1]);
+                }
+            } else {
+                println!("Warning: Test pattern not specified, generating spheres instead");
+            }
+        }
+
+        else if args[i] == "--min-gap" {
+            if i+1 < args.len() {
+                gen_data.min_gap = match args[i+1].parse() {
+                    Ok(c) => Some(c),
+                    Err(_) => {
+                        println!("Warning: Minimum gap not a valid float, spheres may overlap");
+                        None
+                    }
+                }
+            } else {
+                println!("Warning: Minimum gap not specified, spheres may overlap");
+            }
+        }
+
+        else if args[i] == "--attempts" {
+            if i+1 < args.len() {
+                gen_data.placement_attempts = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Number of placement attempts not a valid integer, defaulting to {}", gen_data.placement_attempts);
+                        gen_data.placement_attempts
+                    }
+                }
+            } else {
+                println!("Warning: Number of placement attempts not specified, defaulting to {}", gen_data.placement_attempts);
+            }
+        }
+
+        else if args[i] == "--boundary" {
+            if i+1 < args.len() && ["clip", "inside", "periodic"].contains(&args[i+1].as_str()) {
+                gen_data.boundary = args[i+1].clone();
+            } else {
+                println!("Warning: Boundary policy not specified or unknown, defaulting to {}", gen_data.boundary);
+            }
+        }
+
+        else if args[i] == "--process" {
+            if i+1 < args.len() && placement::PROCESS_NAMES.contains(&args[i+1].as_str()) {
+                gen_data.process = args[i+1].clone();
+            } else {
+                println!("Warning: Placement process not specified or unknown, defaulting to {}", gen_data.process);
+            }
+        }
+
+        else if args[i] == "--clusters" {
+            if i+1 < args.len() {
+                gen_data.cluster_count = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Number of clusters not a valid integer, defaulting to {}", gen_data.cluster_count);
+                        gen_data.cluster_count
+                    }
+                }
+            } else {
+                println!("Warning: Number of clusters not specified, defaulting to {}", gen_data.cluster_count);
+            }
+        }
+
+        else if args[i] == "--cluster-spread" {
+            if i+1 < args.len() {
+                gen_data.cluster_spread = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Cluster spread not a valid float, defaulting to {}", gen_data.cluster_spread);
+                        gen_data.cluster_spread
+                    }
+                }
+            } else {
+                println!("Warning: Cluster spread not specified, defaulting to {}", gen_data.cluster_spread);
+            }
+        }
+
+        else if args[i] == "--interaction" {
+            if i+2 < args.len() {
+                gen_data.interaction_radius = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Interaction radius not a valid float, defaulting to {}", gen_data.interaction_radius);
+                        gen_data.interaction_radius
+                    }
+                };
+                gen_data.interaction_strength = match args[i+2].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Interaction strength not a valid float, defaulting to {}", gen_data.interaction_strength);
+                        gen_data.interaction_strength
+                    }
+                };
+            } else {
+                println!("Warning: Interaction radius and strength not specified, defaulting to {} and {}", gen_data.interaction_radius, gen_data.interaction_strength);
+            }
+        }
+
+        else if args[i] == "--jitter" {
+            if i+1 < args.len() {
+                gen_data.lattice_jitter = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Lattice jitter not a valid float, defaulting to {}", gen_data.lattice_jitter);
+                        gen_data.lattice_jitter
+                    }
+                }
+            } else {
+                println!("Warning: Lattice jitter not specified, defaulting to {}", gen_data.lattice_jitter);
+            }
+        }
+
+        else if args[i] == "--intensity" {
+            if i+1 < args.len() {
+                gen_data.intensity = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Intensity expression not specified, placing spheres uniformly");
+            }
+        }
+
+        else if args[i] == "--mask" {
+            if i+1 < args.len() {
+                gen_data.intensity_mask = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Placement mask not specified, placing spheres uniformly");
+            }
+        }
+
+        else if args[i] == "--lattice" {
+            if i+1 < args.len() && lattice::LATTICE_NAMES.contains(&args[i+1].as_str()) {
+                gen_data.lattice = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Lattice not specified or unknown, placing spheres randomly");
+            }
+        }
+
+        else if args[i] == "--spacing" {
+            if i+1 < args.len() {
+                gen_data.lattice_spacing = match args[i+1].parse::<f32>() {
+                    Ok(c) if c > 0.0 => c,
+                    _ => {
+                        println!("Warning: Lattice spacing not a valid positive float, defaulting to {}", gen_data.lattice_spacing);
+                        gen_data.lattice_spacing
+                    }
+                }
+            } else {
+                println!("Warning: Lattice spacing not specified, defaulting to {}", gen_data.lattice_spacing);
+            }
+        }
+
+        else if args[i] == "--lattice-angles" {
+            if i+3 < args.len() {
+                let angles: Vec<f32> = args[i+1..i+4].iter().filter_map(|a| a.parse().ok()).collect();
+                if angles.len() == 3 {
+                    gen_data.lattice_angles = Vector3Float::new(angles[0], angles[1], angles[2]);
+                } else {
+                    println!("Warning: Lattice angles not valid floats, the lattice is not rotated");
+                }
+            } else {
+                println!("Warning: Lattice angles not specified, the lattice is not rotated");
+            }
+        }
+
+        else if args[i] == "--vacancies" {
+            if i+1 < args.len() {
+                gen_data.vacancy_rate = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Vacancy rate not a valid float, defaulting to {}", gen_data.vacancy_rate);
+                        gen_data.vacancy_rate
+                    }
+                }
+            } else {
+                println!("Warning: Vacancy rate not specified, defaulting to {}", gen_data.vacancy_rate);
+            }
+        }
+
+        else if args[i] == "--substitutions" {
+            if i+1 < args.len() {
+                gen_data.substitution_rate = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Substitution rate not a valid float, defaulting to {}", gen_data.substitution_rate);
+                        gen_data.substitution_rate
+                    }
+                }
+            } else {
+                println!("Warning: Substitution rate not specified, defaulting to {}", gen_data.substitution_rate);
+            }
+        }
+
+        else if args[i] == "--substitute-density" {
+            if i+1 < args.len() {
+                gen_data.substitute_density = match args[i+1].parse() {
+                    Ok(c) => Some(c),
+                    Err(_) => {
+                        println!("Warning: Substitute density not a valid integer, using a random density");
+                        None
+                    }
+                }
+            } else {
+                println!("Warning: Substitute density not specified, using a random density");
+            }
+        }
+
+        else if args[i] == "--settle" {
+            gen_data.settle = true;
+        }
+
+        else if args[i] == "--container" {
+            if i+1 < args.len() && container::CONTAINER_NAMES.contains(&args[i+1].as_str()) {
+                gen_data.container = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Container not specified or unknown, generating without a container");
+            }
+        }
+
+        else if args[i] == "--wall" {
+            if i+1 < args.len() {
+                gen_data.container_wall = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Container wall thickness not a valid float, defaulting to {}", gen_data.container_wall);
+                        gen_data.container_wall
+                    }
+                }
+            } else {
+                println!("Warning: Container wall thickness not specified, defaulting to {}", gen_data.container_wall);
+            }
+        }
+
+        else if args[i] == "--wall-density" {
+            if i+1 < args.len() {
+                gen_data.container_density = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Container wall density not a valid integer, defaulting to {}", gen_data.container_density);
+                        gen_data.container_density
+                    }
+                }
+            } else {
+                println!("Warning: Container wall density not specified, defaulting to {}", gen_data.container_density);
+            }
+        }
+
+        else if args[i] == "--radius-dist" {
+            if i+1 < args.len() && radius::RADIUS_DISTRIBUTIONS.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let parameters: Vec<f32> = args[i+2..].iter().map_while(|a| a.parse().ok()).collect();
+                let needed = match name {
+                    "normal" | "lognormal" | "gamma" => 2,
+                    "powerlaw" => 1,
+                    "discrete" => 2,
+                    _ => 0
+                };
+
+                if name == "empirical" {
+                    if i+2 < args.len() {
+                        gen_data.radius_distribution = args[i+1].clone();
+                        gen_data.radius_histogram = Some(args[i+2].clone());
+                    } else {
+                        println!("Warning: Radius histogram not specified, defaulting to {}", gen_data.radius_distribution);
+                    }
+                } else if parameters.len() < needed || (name == "discrete" && !parameters.len().is_multiple_of(2)) {
+                    println!("Warning: Radius distribution {} needs {} parameters, defaulting to {}", name,
+                        if name == "discrete" { "pairs of" } else if needed == 1 { "1" } else { "2" }, gen_data.radius_distribution);
+                } else {
+                    gen_data.radius_distribution = args[i+1].clone();
+                    gen_data.radius_parameters = parameters;
+                }
+            } else {
+                println!("Warning: Radius distribution not specified or unknown, defaulting to {}", gen_data.radius_distribution);
+            }
+        }
+
+        else if args[i] == "--orientation" {
+            if i+1 < args.len() && orientation::ORIENTATION_NAMES.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let parameters: Vec<f32> = args[i+2..].iter().map_while(|a| a.parse().ok()).collect();
+                let needed = match name {
+                    "aligned" => 1,
+                    "tensor" => 6,
+                    _ => 0
+                };
+                if parameters.len() < needed {
+                    println!("Warning: Orientation distribution {} needs {} parameters, defaulting to {}", name, needed, gen_data.orientation);
+                } else {
+                    gen_data.orientation = args[i+1].clone();
+                    gen_data.orientation_parameters = parameters;
+                }
+            } else {
+                println!("Warning: Orientation distribution not specified or unknown, defaulting to {}", gen_data.orientation);
+            }
+        }
+
+        else if args[i] == "--orientation-axis" {
+            if i+3 < args.len() {
+                let axis: Vec<f32> = args[i+1..i+4].iter().filter_map(|a| a.parse().ok()).collect();
+                if axis.len() == 3 && axis.iter().any(|a| *a != 0.0) {
+                    gen_data.orientation_axis = Vector3Float::new(axis[0], axis[1], axis[2]);
+                } else {
+                    println!("Warning: Orientation axis not three valid floats, defaulting to the z axis");
+                }
+            } else {
+                println!("Warning: Orientation axis not specified, defaulting to the z axis");
+            }
+        }
+
+        else if args[i] == "--overlap" {
+            if i+1 < args.len() && overlap::OVERLAP_POLICIES.contains(&args[i+1].as_str()) {
+                gen_data.overlap = Some(args[i+1].clone());
+            } else {
+                println!("Warning: Overlap policy not specified or unknown, the first instance in the list wins");
+            }
+        }
+
+        else if args[i] == "--overlap-map" {
+            gen_data.overlap_map = true;
+        }
+
+        else if args[i] == "--supersample" {
+            if i+1 < args.len() {
+                gen_data.supersampling = match args[i+1].parse::<u32>() {
+                    Ok(c) if (1..=MAX_SUPERSAMPLING).contains(&c) => c,
+                    _ => {
+                        println!("Warning: Supersampling not an integer in range [1, {}], defaulting to {}", MAX_SUPERSAMPLING, gen_data.supersampling);
+                        gen_data.supersampling
+                    }
+                }
+            } else {
+                println!("Warning: Supersampling not specified, defaulting to {}", gen_data.supersampling);
+            }
+        }
+
+        else if args[i] == "--soft-labels" {
+            gen_data.soft_labels = true;
+        }
+
+        else if args[i] == "--noise" {
+            if i+1 < args.len() && noise::NOISE_MODELS.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let parameters: Vec<f32> = args[i+2..].iter().map_while(|a| a.parse().ok()).collect();
+                let needed = noise::parameter_count(name);
+                if parameters.len() < needed || parameters[..needed].iter().any(|p| *p < 0.0) || (name.starts_with("poisson") && parameters[0] <= 0.0) {
+                    println!("Warning: Noise model {} needs {} non-negative parameters, defaulting to {}", name, needed, gen_data.noise);
+                } else {
+                    gen_data.noise = args[i+1].clone();
+                    gen_data.noise_parameters = parameters[..needed].to_vec();
+                }
+            } else {
+                println!("Warning: Noise model not specified or unknown, defaulting to {}", gen_data.noise);
+            }
+        }
+
+        else if args[i] == "--psf" {
+            if i+1 < args.len() && psf::PSF_NAMES.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let first = if name == "file" { i+3 } else { i+2 };
+                let mut parameters: Vec<f32> = args[first.min(args.len())..].iter().map_while(|a| a.parse().ok()).collect();
+                if name == "gaussian" && (1..3).contains(&parameters.len()) {
+                    parameters = vec![parameters[0]; 3];
+                }
+                let needed = psf::parameter_count(name);
+                if (name == "file" && i+2 >= args.len()) || parameters.len() < needed || parameters[..needed].iter().any(|p| *p <= 0.0) {
+                    println!("Warning: PSF {} needs {} positive parameters{}, no blur is applied", name, needed, if name == "file" { " after the file name" } else { "" });
+                } else {
+                    gen_data.psf = Some(args[i+1].clone());
+                    gen_data.psf_parameters = parameters[..needed].to_vec();
+                    if name == "file" {
+                        gen_data.psf_file = Some(args[i+2].clone());
+                    }
+                }
+            } else {
+                println!("Warning: PSF not specified or unknown, no blur is applied");
+            }
+        }
+
+        else if args[i] == "--bias" {
+            if i+1 < args.len() && bias::BIAS_NAMES.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let mut parameters: Vec<f32> = args[i+2..].iter().map_while(|a| a.parse().ok()).take(2).collect();
+                if name == "polynomial" && parameters.len() == 1 {
+                    parameters.push(bias::DEFAULT_ORDER as f32);
+                }
+                let valid = match name {
+                    "polynomial" => parameters.len() == 2 && parameters[0] >= 0.0 && parameters[1] >= 1.0
+                        && parameters[1] <= bias::MAX_ORDER as f32 && parameters[1].fract() == 0.0,
+                    _ => parameters.len() == 2 && parameters[0] >= 0.0 && parameters[1] > 0.0
+                };
+                if valid {
+                    gen_data.bias = Some(args[i+1].clone());
+                    gen_data.bias_parameters = parameters;
+                } else {
+                    println!("Warning: Bias field {} needs {} valid parameters, no bias is applied", name,
+                        if name == "polynomial" { "a strength and an optional order of 1 to 5, as" } else { "a strength and a correlation length, as" });
+                }
+            } else {
+                println!("Warning: Bias field not specified or unknown, no bias is applied");
+            }
+        }
+
+        else if args[i] == "--range" {
+            if i+2 < args.len() {
+                match (args[i+1].parse::<f32>(), args[i+2].parse::<f32>()) {
+                    (Ok(low), Ok(high)) if low < high => {
+                        range = Some((low, high));
+                    },
+                    _ => {
+                        println!("Warning: Output range not two increasing values, defaulting to the range of the data type");
+                    }
+                }
+            } else {
+                println!("Warning: Output range not specified, defaulting to the range of the data type");
+            }
+        }
+
+        else if args[i] == "--type" {
+            if i+1 < args.len() && DATA_TYPES.contains(&args[i+1].as_str()) {
+                gen_data.data_type = args[i+1].clone();
+            } else {
+                println!("Warning: Data type not specified or unknown, defaulting to {}", gen_data.data_type);
+            }
+        }
+
+        else if args[i] == "--range-policy" {
+            if i+1 < args.len() && range::RANGE_POLICIES.contains(&args[i+1].as_str()) {
+                gen_data.range_policy = args[i+1].clone();
+            } else {
+                println!("Warning: Range policy not specified or unknown, defaulting to {}", gen_data.range_policy);
+            }
+        }
+
+        else if args[i] == "--texture" || args[i] == "--background-texture" {
+            let texture = texture::Texture::parse(&args[i+1..]);
+            if texture.is_none() {
+                println!("Warning: Texture needs a name ({}), a positive scale, 1 to {} octaves and an amplitude, defaulting to none",
+                    texture::TEXTURE_NAMES.join(", "), texture::MAX_OCTAVES);
+            }
+            if args[i] == "--texture" {
+                gen_data.texture = texture;
+            } else {
+                gen_data.background_texture = texture;
+            }
+        }
+
+        else if args[i] == "--profile" {
+            if i+1 < args.len() && profile::PROFILE_NAMES.contains(&args[i+1].as_str()) {
+                let name = args[i+1].as_str();
+                let ranges: Vec<f32> = args[i+2..].iter().map_while(|a| a.parse().ok()).collect();
+                let needed = 2 * profile::parameter_count(name);
+                if ranges.len() < needed {
+                    println!("Warning: Profile {} needs {} parameters (a range for each), defaulting to {}", name, needed, gen_data.profile);
+                } else {
+                    gen_data.profile = args[i+1].clone();
+                    gen_data.profile_ranges = ranges[..needed].to_vec();
+                }
+            } else {
+                println!("Warning: Profile not specified or unknown, defaulting to {}", gen_data.profile);
+            }
+        }
+
+        else if args[i] == "--fraction" {
+            if i+1 < args.len() {
+                gen_data.volume_fraction = match args[i+1].parse::<f32>() {
+                    Ok(c) if c > 0.0 && c < 1.0 => Some(c),
+                    _ => {
+                        println!("Warning: Volume fraction not a valid float in range (0, 1), using the number of instances instead");
+                        None
+                    }
+                }
+            } else {
+                println!("Warning: Volume fraction not specified, using the number of instances instead");
+            }
+        }
+
+        else if args[i] == "--packing" {
+            if i+1 < args.len() && ["auto", "rsa", "dense"].contains(&args[i+1].as_str()) {
+                gen_data.packing = args[i+1].clone();
+            } else {
+                println!("Warning: Packing algorithm not specified or unknown, defaulting to {}", gen_data.packing);
+            }
+        }
+
+        else if args[i] == "--discs" {
+            if i+1 < args.len() {
+                gen_data.disc_count = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Number of discs not a valid integer, defaulting to {}", gen_data.disc_count);
+                        gen_data.disc_count
+                    }
+                }
+            } else {
+                println!("Warning: Number of discs not specified, defaulting to {}", gen_data.disc_count);
+            }
+        }
+
+        else if args[i] == "--sheets" {
+            if i+1 < args.len() {
+                gen_data.sheet_count = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Number of sheets not a valid integer, defaulting to {}", gen_data.sheet_count);
+                        gen_data.sheet_count
+                    }
+                }
+            } else {
+                println!("Warning: Number of sheets not specified, defaulting to {}", gen_data.sheet_count);
+            }
+        }
+
+        else if args[i] == "--thickness" {
+            if i+2 < args.len() {
+                gen_data.min_thin_thickness = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Bottom thickness limit not a valid float, defaulting to {}", gen_data.min_thin_thickness);
+                        gen_data.min_thin_thickness
+                    }
+                };
+                gen_data.max_thin_thickness = match args[i+2].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Top thickness limit not a valid float, defaulting to {}", gen_data.max_thin_thickness);
+                        gen_data.max_thin_thickness
+                    }
+                };
+            } else {
+                println!("Warning: Thickness limits not specified, defaulting to {} and {}", gen_data.min_thin_thickness, gen_data.max_thin_thickness);
+            }
+        }
+
+        else if args[i] == "--thin-radius" {
+            if i+2 < args.len() {
+                gen_data.min_thin_radius = match args[i+1].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Bottom thin feature radius not a valid float, defaulting to {}", gen_data.min_thin_radius);
+                        gen_data.min_thin_radius
+                    }
+                };
+                gen_data.max_thin_radius = match args[i+2].parse() {
+                    Ok(c) => c,
+                    Err(_) => {
+                        println!("Warning: Top thin feature radius not a valid float, defaulting to {}", gen_data.max_thin_radius);
+                        gen_data.max_thin_radius
+                    }
+                };
+            } else {
+                println!("Warning: Thin feature radius limits not specified, defaulting to {} and {}", gen_data.min_thin_radius, gen_data.max_thin_radius);
+            }
+        }
+
+        else if args[i] == "--thin-density" {
+            if i+1 < args.len() {
+                gen_data.thin_density = match args[i+1].parse() {
+                    Ok(c) => Some(c),
+                    Err(_) => {
+                        println!("Warning: Thin feature density not a valid integer, using random densities");
+                        None
+                    }
+                }
+            } else {
+                println!("Warning: Thin feature density not specified, using random densities");
+            }
+        }
+
+        else if args[i] == "--extent" {
+            let values: Vec<f32> = args.iter().skip(i+1).take(3).filter_map(|a| a.parse().ok()).collect();
+            if values.len() == 3 && values.iter().all(|v| *v > 0.0) {
+                extent = Some(Vector3Float::new(values[0], values[1], values[2]));
+            } else {
+                println!("Warning: Physical extent not specified as three positive floats, using the voxel spacing");
+            }
+        }
+
+        else if args[i] == "--voxel-spacing" {
+            let values: Vec<f32> = args.iter().skip(i+1).take(3).map_while(|a| a.parse().ok()).collect();
+            if values.iter().any(|v| *v <= 0.0) {
+                println!("Warning: Voxel spacing has to be positive, using relative units");
+            } else if values.len() == 3 {
+                voxel_spacing = Some(Vector3Float::new(values[0], values[1], values[2]));
+            } else if values.len() == 1 {
+                voxel_spacing = Some(Vector3Float::new(values[0], values[0], values[0]));
+            } else {
+                println!("Warning: Voxel spacing not specified as one or three floats, using relative units");
+            }
+        }
+
+        else if args[i] == "--unit" {
+            if i+1 < args.len() {
+                gen_data.unit = args[i+1].clone();
+            } else {
+                println!("Warning: Unit not specified");
+            }
+        }
+
+        else if args[i] == "--cpu" {
+            gen_data.use_cpu = true;
+        }
+
+        else if args[i] == "-h" {
+            println!("-----------------------------------------------------------");
+            println!("This is a small tool for the creation of testing volumes.\n");
+            println!("This tool outputs four files:");
+            println!("  * .raw file with volumetric data as a sequence of samples of the --type data type (unsigned 8-bit integers by default)");
+            println!("  * .sgm file with space segmented into classes as a sequence of unsigned 32-bit integers");
+            println!("  * .txt file containing settings used for data generation");
+            println!("  * .csv file with information about generated spheres (such as radius, position, id...) or scene objects");
+            println!("Optional outputs are _overlaps.raw (--overlap-map), _occupancy.raw (--soft-labels) and _bias.raw (--bias).\n");
+            println!("The supported parameters are:");
+            println!("  * -h  Shows this help message.");
+            println!("  * -o  Output name to append to generated files. Defaults to {}.", gen_data.generation_name);
+            println!("  * -v  Number of variations. Generates and exports this many volumes with given settings, but different hidden parameters (instance positions etc.). Defaults to {}.", gen_data.variation_count);
+            println!("  * -i  Number of instances (spheres) to put inside the volume. Defaults to {}.", gen_data.sphere_count);
+            println!("  * -r  Interval from which to uniformly sample a single sphere radius. Specified as two floats, separated with a whitespace. If only one float is provided, the range is [0, given radius]. Defaults to [{}-{}]", gen_data.min_sphere_radius, gen_data.max_sphere_radius);
+            println!("  * -n  Value in the range of the data type, up to which the values are considered empty space. Can be treated as the largest possible density of the empty space. Defaults to {}.", gen_data.empty_space);
+            println!("  * -q  Quality of the volume, given as the variance of the classic noise inside instances. Larger means more noise. Defaults to {}.", gen_data.noise_span);
+            println!("  * -d  Dimensions of the volume, provided as three integers separated by 'x'. Defaults to {}x{}x{}.", gen_data.pixel_dimensions.x, gen_data.pixel_dimensions.y, gen_data.pixel_dimensions.z);
+            println!("  * --voxel-spacing  Physical size of a voxel, as one float or three floats for x, y and z. Positions, radii and all other lengths are then given in physical units, and spheres stay round for anisotropic spacings. By default the largest axis of the volume has length 1 and voxels are cubic.");
+            println!("  * --extent  Physical size of the volume along x, y and z, used instead of --voxel-spacing. The voxel spacing is the extent divided by the dimensions.");
+            println!("  * --unit  Name of the physical unit written to the execution data, such as mm or um. Defaults to mm when --voxel-spacing or --extent is given.");
+            println!("  * --scene  Scene file with objects described as CSG trees (union, intersection, difference of spheres, ellipsoids, cylinders and boxes). Each object is labelled as one instance. Replaces the randomly placed spheres.");
+            println!("  * --from-csv  Renders the instances of a .csv file written by an earlier run (sphere or instance data) again, for example at another resolution or noise level. Replaces the randomly placed spheres.");
+            println!("  * --phantom  Renders a built-in phantom instead of random spheres, each ellipsoid or insert is labelled as one instance. One of: {}. Shepp-Logan intensities are mapped onto densities 0 to 255 through the window [1.0, 1.05] for the original phantom, where the skull saturates, and [0.0, 1.0] for the modified one.", phantom::PHANTOM_NAMES.join(", "));
+            println!("  * --pattern  Renders a resolution test pattern instead of random spheres. One of: {}. Sizes of bars and points are given in voxels of the chosen dimensions.", pattern::PATTERN_NAMES.join(", "));
+            println!("  * --min-gap  Places spheres without overlapping, keeping at least this distance between their surfaces (0 for touching spheres). By default spheres overlap freely.");
+            println!("  * --attempts  Number of random positions tried for each sphere when placing without overlap. Spheres that do not fit are dropped and the placed count is reported. Defaults to {}.", gen_data.placement_attempts);
+            println!("  * --boundary  How instances meet the volume faces: clip (cut off by the faces), inside (spheres are placed fully inside) or periodic (instances crossing a face wrap around with the same label, so the volume tiles seamlessly). Defaults to {}.", gen_data.boundary);
+            println!("  * --process  Spatial point process for sphere origins: poisson (uniform), thomas (Gaussian clusters), matern (uniform clusters in a ball), strauss (inhibition between close spheres) or lattice (regular grid with jitter). Defaults to {}.", gen_data.process);
+            println!("  * --clusters  Number of cluster centers for the thomas and matern processes. Defaults to {}.", gen_data.cluster_count);
+            println!("  * --cluster-spread  Standard deviation (thomas) or radius (matern) of the clusters. Defaults to {}.", gen_data.cluster_spread);
+            println!("  * --interaction  Interaction radius and strength in [0, 1] of the strauss process, every sphere closer than the radius multiplies the acceptance probability by the strength (0 for a hard-core process). Defaults to {} {}.", gen_data.interaction_radius, gen_data.interaction_strength);
+            println!("  * --jitter  Random displacement of the lattice process and crystal lattice sites, as a fraction of the lattice spacing. Defaults to {}.", gen_data.lattice_jitter);
+            println!("  * --intensity  Expression of x, y, z and r (distance from the centre) giving the relative placement intensity of spheres, for example 'x' or 'exp(-r^2/0.02)'. Supports + - * / ^ and exp, sqrt, abs, sin, cos, min, max.");
+            println!("  * --mask  .raw file (8 bits, volume dimensions) restricting sphere placement to its non-zero voxels, with the voxel values as relative intensities. Used instead of --intensity.");
+            println!("  * --lattice  Places spheres on a crystal lattice instead of randomly: sc, bcc, fcc or hcp. Sphere radii are still sampled from -r, and the lattice indices are written to the .csv file.");
+            println!("  * --spacing  Lattice constant (nearest neighbour distance for hcp), a positive length in scene units. Defaults to {}.", gen_data.lattice_spacing);
+            println!("  * --lattice-angles  Rotation of the lattice around the volume centre, as three angles in degrees around x, y and z. Defaults to 0 0 0.");
+            println!("  * --vacancies  Fraction of lattice sites left empty. Defaults to {}.", gen_data.vacancy_rate);
+            println!("  * --substitutions  Fraction of lattice spheres with the substitute density. Defaults to {}.", gen_data.substitution_rate);
+            println!("  * --substitute-density  Density of substituted spheres, all other lattice spheres share one host density. Defaults to a random density.");
+            println!("  * --settle  Drops the spheres one by one into the volume and lets them settle under gravity along -z into a bed, until -i spheres are placed or the bed reaches the top.");
+            println!("  * --container  Sample holder around the instances: cylinder, tube (open on both ends) or box, standing along z. Spheres are placed inside it, the background only fills its inside and the outside is air (density 0). The wall is labelled as its own instance.");
+            println!("  * --wall  Wall thickness of the container, in scene units. Defaults to {}.", gen_data.container_wall);
+            println!("  * --wall-density  Density of the container wall. Defaults to {}.", gen_data.container_density);
+            println!("  * --radius-dist  Distribution of sphere radii, followed by its parameters: uniform (on the -r range), normal <mean> <deviation>, lognormal <median> <sigma>, gamma <shape> <scale>, powerlaw <exponent> (p(r) ~ r^-exponent), discrete <radius> <weight> ... or empirical <histogram.csv> (lines of lower radius, upper radius and count). Continuous distributions are truncated to the -r range. Defaults to {}.", gen_data.radius_distribution);
+            println!("  * --profile  Density profile inside each sphere, given with a range for every parameter that is sampled per sphere: flat, linear <min> <max> (falloff, the surface has 1 - falloff of the density), gaussian <min> <max> (width relative to the radius), core-shell <min> <max> <min> <max> (core radius fraction and shell density) or halo <min> <max> <min> <max> (rim width relative to the radius and strength, negative for a dark rim). The sampled parameters are written to the .csv file. Defaults to {}.", gen_data.profile);
+            println!("  * --fraction  Fills the volume with spheres up to this volume fraction instead of placing the number given by -i. Spheres are kept fully inside the volume unless it is periodic, and the fraction measured on the voxel grid is written to the execution data.");
+            println!("  * --packing  Algorithm for --fraction: rsa (random sequential addition), dense (force-biased growth, for near-jammed packings) or auto, which uses dense above a fraction of {}. Defaults to {}.", packing::DENSE_FRACTION, gen_data.packing);
+            println!("  * --discs  Number of randomly oriented thin discs (such as cracks) added to the volume, each with its own label. Defaults to {}.", gen_data.disc_count);
+            println!("  * --sheets  Number of randomly oriented curved sheets (such as membranes) added to the volume, each with its own label. Defaults to {}.", gen_data.sheet_count);
+            println!("  * --thickness  Interval from which to uniformly sample the thickness of discs and sheets, in voxels. Thin features are partial-volume rendered, so they stay visible below one voxel. Defaults to [{}-{}].", gen_data.min_thin_thickness, gen_data.max_thin_thickness);
+            println!("  * --thin-radius  Interval from which to uniformly sample the radius of discs and sheets. Defaults to [{}-{}].", gen_data.min_thin_radius, gen_data.max_thin_radius);
+            println!("  * --thin-density  Density of discs and sheets, for example 0 for cracks. Random like the spheres if not given.");
+            println!("  * --orientation  Distribution of the normals of discs and sheets, which are also spun by a uniform angle around them: uniform (uniform rotations), aligned <concentration> (von Mises-Fisher around --orientation-axis, 0 is uniform), planar (normals uniform in the plane perpendicular to --orientation-axis) or tensor <a11> <a22> <a33> <a12> <a13> <a23> (reproduces the given orientation tensor, normalized to a trace of 1). The realised orientation tensor is written to the execution data. Defaults to {}.", gen_data.orientation);
+            println!("  * --orientation-axis  Preferred axis of the aligned orientation distribution and plane normal of the planar one, as three floats. Defaults to 0 0 1.");
+            println!("  * --overlap  How voxels inside several instances are resolved: smallest, largest or order (the smallest or largest instance by bounding box, or the first inserted one, labels the voxel and gives its density), max-density (the densest instance wins), add or average (densities of all instances are added, up to 255, or averaged, and the first instance in the list labels the voxel). By default the first instance in the list wins, spheres are sorted smallest first and scene objects keep their order.");
+            println!("  * --overlap-map  Writes an additional 8-bit _overlaps.raw volume counting how many instances cover each voxel.");
+            println!("  * --supersample  Number of points per axis each voxel is sampled at, the density is their mean so instance boundaries get mixed densities and spheres smaller than a voxel stay visible. Labels are still taken at the voxel center. Defaults to {}.", gen_data.supersampling);
+            println!("  * --psf  Point spread function the clean density is blurred with before noise is added, lengths in scene units: gaussian <sigma> or <sigma x> <sigma y> <sigma z>, airy <wavelength> <numerical aperture> (2D Airy disc within each z slice, with no blur along z, use widefield for an axial extent), widefield <wavelength> <numerical aperture> <refractive index> (Airy disc with a Gaussian along z of the axial resolution) or file <path> <x> <y> <z> (centered big-endian 32-bit float volume with the voxel spacing of the output). Defaults to none.");
+            println!("  * --bias  Smooth multiplicative bias field applied after the PSF and before noise: polynomial <strength> [<order>] (exponential of random Legendre polynomials up to the order, {} by default, with coefficients up to the strength) or smooth <strength> <length> (exponential of a smooth random field with the correlation length in scene units, scaled by the strength). The field is written to _bias.raw as big-endian 32-bit floats. Defaults to none.", bias::DEFAULT_ORDER);
+            println!("  * --noise  Noise model added to the rendered density: classic (Gaussian with the -q value as variance inside instances and half the -n value in the background), gaussian <sigma>, poisson <counts per density unit>, poisson-gaussian <counts per density unit> <sigma>, rician <sigma> (magnitude of complex Gaussian noise), speckle <sigma> (multiplicative), salt-and-pepper <fraction> or correlated <sigma> <length> (Gaussian noise smoothed to the correlation length in scene units). Defaults to {}.", gen_data.noise);
+            println!("  * --type  Sample type of the density volume: {}. All densities, including -n, -q and --range, are given in its range, f32 uses the scale of u8 without rounding, with 255 as its largest density and no lower limit, so --range may start below 0 to keep negative noise. Types wider than 8 bits are written big-endian. Defaults to {}.", DATA_TYPES.join(", "), gen_data.data_type);
+            println!("  * --range  Smallest and largest output density, noisy values outside are handled by --range-policy and counted in the .txt file. Must fit the data type, defaults to 0 and its largest value for every type, including i16 and f32.");
+            println!("  * --range-policy  How densities outside the output range are handled: clamp (set to the nearest bound), reflect (mirrored back at the bounds) or rescale (the span of all densities is mapped linearly onto the range). Defaults to {}.", gen_data.range_policy);
+            println!("  * --texture  Procedural texture added to the density inside solid instances, given as <perlin|simplex> <scale> <octaves> <amplitude>. The scale is the size of the coarsest features in scene units, every further octave adds features of half the size and half the weight, and the amplitude is the largest density change. Every instance gets its own texture. Defaults to none.");
+            println!("  * --background-texture  Procedural texture added to the background density, with the same parameters as --texture. Defaults to none.");
+            println!("  * --soft-labels  Writes an additional _occupancy.raw volume with the fraction of each voxel filled by instances, as big-endian 32-bit floats. Measured at the --supersample points, thin features use their exact coverage.");
+            println!("  * --cpu  Generate the volume on the CPU instead of the OpenCL device.");
+            println!("-----------------------------------------------------------");
+            return None;
+        }
+    }
+
+    // Without a physical size, the largest axis has length 1 and voxels are cubic
+    let dims = &gen_data.pixel_dimensions;
+    gen_data.extent = match (&extent, &voxel_spacing) {
+        (Some(e), _) => e.clone(),
+        (None, Some(s)) => Vector3Float::new(s.x * dims.x as f32, s.y * dims.y as f32, s.z * dims.z as f32),
+        (None, None) => {
+            let largest = dims.x.max(dims.y).max(dims.z) as f32;
+            Vector3Float::new(dims.x as f32 / largest, dims.y as f32 / largest, dims.z as f32 / largest)
+        }
+    };
+    if gen_data.unit.is_empty() && (extent.is_some() || voxel_spacing.is_some()) {
+        gen_data.unit = String::from("mm");
+    }
+
+    // The output range depends on the data type, which may be given after it
+    let (type_min, type_max) = gen_data.density_limits();
+    gen_data.range = match range {
+        Some((low, high)) if low >= type_min && high <= type_max => (low, high),
+        Some(_) => {
+            println!("Warning: Output range does not fit the data type {}, defaulting to (0, {})", gen_data.data_type, type_max);
+            (0.0, type_max)
+        },
+        None => (0.0, type_max)
+    };
     return Some(gen_data);
 }
 
@@ -0,0 +1,127 @@
+use rand::{Rng, rngs::ThreadRng};
+
+use crate::{GeneratorData, psf, placement::normal_sample};
+
+pub const NOISE_MODELS: [&str; 8] = ["classic", "gaussian", "poisson", "poisson-gaussian", "rician", "speckle", "salt-and-pepper", "correlated"];
+
+/// Poisson counts with a larger mean are drawn from the normal approximation.
+const POISSON_NORMAL_LIMIT: f32 = 30.0;
+
+/// Number of parameters of the noise model.
+pub fn parameter_count(name: &str) -> usize {
+    match name {
+        "gaussian" | "poisson" | "rician" | "speckle" | "salt-and-pepper" => 1,
+        "poisson-gaussian" | "correlated" => 2,
+        _ => 0
+    }
+}
+
+fn poisson_sample(rand: &mut ThreadRng, mean: f32) -> f32 {
+    if mean <= 0.0 {
+        return 0.0;
+    }
+    if mean > POISSON_NORMAL_LIMIT {
+        return (mean + mean.sqrt() * normal_sample(rand)).round().max(0.0);
+    }
+    // Knuth's method, multiplies uniform numbers until the product drops below exp(-mean)
+    let limit = (-mean).exp();
+    let mut count = 0.0;
+    let mut product = rand.gen::<f32>();
+    while product > limit {
+        count += 1.0;
+        product *= rand.gen::<f32>();
+    }
+    return count;
+}
+
+/// Gaussian white noise smoothed with a Gaussian of the correlation length, scaled back to the standard deviation.
+/// The smoothing wraps around the faces, so the noise is stationary up to the edges.
+fn correlated_field(gen_data: &GeneratorData, rand: &mut ThreadRng, sigma: f32, length: f32) -> Vec<f32> {
+    let d = &gen_data.pixel_dimensions;
+    let dims = [d.x, d.y, d.z];
+    let spacing = gen_data.spacing();
+    let mut field: Vec<f32> = (0..d.x * d.y * d.z).map(|_| normal_sample(rand)).collect();
+    let mut gain = 1.0;
+    for (axis, h) in [spacing.x, spacing.y, spacing.z].iter().enumerate() {
+        let kernel = psf::gaussian_kernel(length / h);
+        gain *= kernel.iter().map(|w| w * w).sum::<f32>().sqrt();
+        field = psf::convolve_axis(&field, dims, axis, &kernel, true);
+    }
+    return field.into_iter().map(|v| v * sigma / gain).collect();
+}
+
+/// Adds noise of the selected model to the clean densities. The classic model uses the -q value as the variance
+/// inside instances and half the empty space density in every unlabelled voxel, including air outside containers.
+/// All other models act on every voxel.
+pub fn apply_noise(gen_data: &GeneratorData, densities: &mut Vec<f32>, materials: &Vec<u32>) {
+    let mut rand = rand::thread_rng();
+    let p = &gen_data.noise_parameters;
+    match gen_data.noise.as_str() {
+        "gaussian" => {
+            for d in densities.iter_mut() {
+                *d += p[0] * normal_sample(&mut rand);
+            }
+        },
+        "poisson" | "poisson-gaussian" => {
+            let sigma = if gen_data.noise == "poisson-gaussian" { p[1] } else { 0.0 };
+            for d in densities.iter_mut() {
+                *d = poisson_sample(&mut rand, *d * p[0]) / p[0] + sigma * normal_sample(&mut rand);
+            }
+        },
+        "rician" => {
+            for d in densities.iter_mut() {
+                let real = *d + p[0] * normal_sample(&mut rand);
+                let imaginary = p[0] * normal_sample(&mut rand);
+                *d = (real * real + imaginary * imaginary).sqrt();
+            }
+        },
+        "speckle" => {
+            for d in densities.iter_mut() {
+                *d *= 1.0 + p[0] * normal_sample(&mut rand);
+            }
+        },
+        "salt-and-pepper" => {
+            // Pepper and salt are the bounds of the output range
+            let (low, high) = gen_data.range;
+            for d in densities.iter_mut() {
+                let u = rand.gen::<f32>();
+                if u < p[0] / 2.0 {
+                    *d = low;
+                } else if u < p[0] {
+                    *d = high;
+                }
+            }
+        },
+        "correlated" => {
+            let field = correlated_field(gen_data, &mut rand, p[0], p[1]);
+            for (d, n) in densities.iter_mut().zip(field.iter()) {
+                *d += n;
+            }
+        },
+        _ => {
+            for (d, m) in densities.iter_mut().zip(materials.iter()) {
+                let variance = if *m != 0 {
+                    gen_data.noise_span as f32
+                } else {
+                    gen_data.empty_space as f32 / 2.0
+                };
+                *d += variance.sqrt() * normal_sample(&mut rand);
+            }
+        }
+    }
+}
+
+/// Noise model and parameters for the execution data.
+pub fn describe(gen_data: &GeneratorData) -> String {
+    let p = &gen_data.noise_parameters;
+    match gen_data.noise.as_str() {
+        "gaussian" => format!("gaussian, sigma {}", p[0]),
+        "poisson" => format!("poisson, {} counts per density unit", p[0]),
+        "poisson-gaussian" => format!("poisson-gaussian, {} counts per density unit, sigma {}", p[0], p[1]),
+        "rician" => format!("rician, sigma {}", p[0]),
+        "speckle" => format!("speckle, sigma {}", p[0]),
+        "salt-and-pepper" => format!("salt-and-pepper, fraction {}", p[0]),
+        "correlated" => format!("correlated, sigma {}, correlation length {}", p[0], p[1]),
+        _ => format!("classic, variance {} in instances and {} in the background", gen_data.noise_span, gen_data.empty_space as f32 / 2.0)
+    }
+}
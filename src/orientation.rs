@@ -0,0 +1,244 @@
+use std::{f32::consts::PI, io::{Error, ErrorKind}};
+
+use rand::Rng;
+
+use crate::{GeneratorData, vector3float::Vector3Float, placement::normal_sample};
+
+pub const ORIENTATION_NAMES: [&str; 4] = ["uniform", "aligned", "planar", "tensor"];
+
+/// Directions drawn per iteration when fitting the distribution to a given orientation tensor.
+const TENSOR_SAMPLES: usize = 20000;
+const TENSOR_ITERATIONS: usize = 30;
+const JACOBI_SWEEPS: usize = 50;
+
+fn normalized(v: &Vector3Float) -> Vector3Float {
+    let l = v.length();
+    return Vector3Float::new(v.x / l, v.y / l, v.z / l);
+}
+
+fn cross(a: &Vector3Float, b: &Vector3Float) -> Vector3Float {
+    return Vector3Float::new(a.y*b.z - a.z*b.y, a.z*b.x - a.x*b.z, a.x*b.y - a.y*b.x);
+}
+
+/// Two unit vectors perpendicular to the direction and to each other.
+fn perpendicular_basis(d: &Vector3Float) -> (Vector3Float, Vector3Float) {
+    let helper = if d.x.abs() < 0.9 { Vector3Float::new(1.0, 0.0, 0.0) } else { Vector3Float::new(0.0, 1.0, 0.0) };
+    let u = normalized(&cross(&helper, d));
+    let v = cross(d, &u);
+    return (u, v);
+}
+
+fn uniform_direction() -> Vector3Float {
+    let mut rand = rand::thread_rng();
+    let z = 1.0 - 2.0 * rand.gen::<f32>();
+    let azimuth = 2.0 * PI * rand.gen::<f32>();
+    let s = (1.0 - z * z).max(0.0).sqrt();
+    return Vector3Float::new(s * azimuth.cos(), s * azimuth.sin(), z);
+}
+
+/// Eigenvalues and eigenvectors (rows) of a symmetric matrix, by cyclic Jacobi rotations.
+fn symmetric_eigen(m: &[[f32; 3]; 3]) -> ([f32; 3], [[f32; 3]; 3]) {
+    let mut a = *m;
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+    for _ in 0..JACOBI_SWEEPS {
+        let off = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+        if off < 1e-9 {
+            break;
+        }
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-12 {
+                continue;
+            }
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+            for row in a.iter_mut() {
+                let (akp, akq) = (row[p], row[q]);
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (ap, aq) = (a[p], a[q]);
+            a[p] = std::array::from_fn(|k| c * ap[k] - s * aq[k]);
+            a[q] = std::array::from_fn(|k| s * ap[k] + c * aq[k]);
+            let (vp, vq) = (v[p], v[q]);
+            v[p] = std::array::from_fn(|k| c * vp[k] - s * vq[k]);
+            v[q] = std::array::from_fn(|k| s * vp[k] + c * vq[k]);
+        }
+    }
+    return ([a[0][0], a[1][1], a[2][2]], v);
+}
+
+/// Second-order orientation tensor, the mean outer product of the directions.
+pub fn orientation_tensor(directions: &[Vector3Float]) -> [[f32; 3]; 3] {
+    let mut t = [[0.0; 3]; 3];
+    for d in directions {
+        let c = [d.x, d.y, d.z];
+        for i in 0..3 {
+            for j in 0..3 {
+                t[i][j] += c[i] * c[j];
+            }
+        }
+    }
+    let n = directions.len().max(1) as f32;
+    for row in t.iter_mut() {
+        for value in row.iter_mut() {
+            *value /= n;
+        }
+    }
+    return t;
+}
+
+pub fn describe_tensor(t: &[[f32; 3]; 3]) -> String {
+    let rows: Vec<String> = t.iter().map(|r| format!("({:.4}, {:.4}, {:.4})", r[0], r[1], r[2])).collect();
+    return format!("({})", rows.join(", "));
+}
+
+/// Distribution of the axis of anisotropic instances, the local z axis of discs and sheets.
+/// Instances are also spun around their axis by a uniform angle, so the uniform distribution
+/// gives rotations uniform on SO(3).
+pub enum OrientationDistribution {
+    Uniform,
+    /// Von Mises-Fisher distribution around the axis, uniform for a concentration of 0
+    Aligned { axis: Vector3Float, concentration: f32 },
+    /// Axes uniform in the plane with the given normal
+    Planar { normal: Vector3Float },
+    /// Angular central Gaussian whose scales along the eigenvectors of the tensor were fitted to reproduce it
+    Tensor { eigenvectors: [[f32; 3]; 3], scales: [f32; 3] }
+}
+
+impl OrientationDistribution {
+    pub fn new(gen_data: &GeneratorData) -> Result<Self, Error> {
+        let p = &gen_data.orientation_parameters;
+        let axis = &gen_data.orientation_axis;
+        if axis.length() == 0.0 {
+            return Err(Error::new(ErrorKind::InvalidInput, "Orientation axis must not be zero"));
+        }
+        let axis = normalized(axis);
+
+        match gen_data.orientation.as_str() {
+            "aligned" => {
+                return Ok(OrientationDistribution::Aligned { axis, concentration: p.first().copied().unwrap_or(0.0).max(0.0) });
+            },
+            "planar" => {
+                return Ok(OrientationDistribution::Planar { normal: axis });
+            },
+            "tensor" => {
+                if p.len() < 6 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Orientation tensor needs 6 components"));
+                }
+                let trace = p[0] + p[1] + p[2];
+                if trace <= 0.0 {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Orientation tensor must have a positive trace"));
+                }
+                let target = [
+                    [p[0] / trace, p[3] / trace, p[4] / trace],
+                    [p[3] / trace, p[1] / trace, p[5] / trace],
+                    [p[4] / trace, p[5] / trace, p[2] / trace]
+                ];
+                let (eigenvalues, eigenvectors) = symmetric_eigen(&target);
+                if eigenvalues.iter().any(|e| *e < -1e-4) {
+                    return Err(Error::new(ErrorKind::InvalidInput, "Orientation tensor must be positive semi-definite"));
+                }
+                let scales = Self::fit_scales(&eigenvalues.map(|e| e.max(0.0)));
+                return Ok(OrientationDistribution::Tensor { eigenvectors, scales });
+            },
+            _ => {
+                return Ok(OrientationDistribution::Uniform);
+            }
+        }
+    }
+
+    /// Direction in eigenvector coordinates from the angular central Gaussian with the given variances.
+    fn central_gaussian(scales: &[f32; 3]) -> [f32; 3] {
+        let mut rand = rand::thread_rng();
+        loop {
+            let x = [scales[0].sqrt() * normal_sample(&mut rand), scales[1].sqrt() * normal_sample(&mut rand), scales[2].sqrt() * normal_sample(&mut rand)];
+            let l = (x[0]*x[0] + x[1]*x[1] + x[2]*x[2]).sqrt();
+            if l > 0.0 {
+                return [x[0] / l, x[1] / l, x[2] / l];
+            }
+        }
+    }
+
+    /// Fixed-point iteration on the variances, until the diagonal of the sampled tensor matches the eigenvalues.
+    fn fit_scales(eigenvalues: &[f32; 3]) -> [f32; 3] {
+        let mut scales = *eigenvalues;
+        for _ in 0..TENSOR_ITERATIONS {
+            let mut diagonal = [0.0; 3];
+            for _ in 0..TENSOR_SAMPLES {
+                let d = Self::central_gaussian(&scales);
+                for axis in 0..3 {
+                    diagonal[axis] += d[axis] * d[axis] / TENSOR_SAMPLES as f32;
+                }
+            }
+            for axis in 0..3 {
+                if diagonal[axis] > 0.0 {
+                    scales[axis] *= eigenvalues[axis] / diagonal[axis];
+                }
+            }
+        }
+        return scales;
+    }
+
+    pub fn sample_axis(&self) -> Vector3Float {
+        let mut rand = rand::thread_rng();
+        match self {
+            OrientationDistribution::Uniform => uniform_direction(),
+            OrientationDistribution::Aligned { axis, concentration } => {
+                if *concentration < 1e-6 {
+                    return uniform_direction();
+                }
+                // Inverse transform of the cosine to the axis, stable for large concentrations
+                let k = *concentration;
+                let u = rand.gen::<f32>();
+                let w = (1.0 + (u + (1.0 - u) * (-2.0 * k).exp()).ln() / k).clamp(-1.0, 1.0);
+                let azimuth = 2.0 * PI * rand.gen::<f32>();
+                let (a, b) = perpendicular_basis(axis);
+                let s = (1.0 - w * w).sqrt();
+                return Vector3Float::new(
+                    w * axis.x + s * (azimuth.cos() * a.x + azimuth.sin() * b.x),
+                    w * axis.y + s * (azimuth.cos() * a.y + azimuth.sin() * b.y),
+                    w * axis.z + s * (azimuth.cos() * a.z + azimuth.sin() * b.z)
+                );
+            },
+            OrientationDistribution::Planar { normal } => {
+                let azimuth = 2.0 * PI * rand.gen::<f32>();
+                let (a, b) = perpendicular_basis(normal);
+                let (s, c) = azimuth.sin_cos();
+                return Vector3Float::new(c * a.x + s * b.x, c * a.y + s * b.y, c * a.z + s * b.z);
+            },
+            OrientationDistribution::Tensor { eigenvectors, scales } => {
+                let d = Self::central_gaussian(scales);
+                let v = eigenvectors;
+                return Vector3Float::new(
+                    d[0] * v[0][0] + d[1] * v[1][0] + d[2] * v[2][0],
+                    d[0] * v[0][1] + d[1] * v[1][1] + d[2] * v[2][1],
+                    d[0] * v[0][2] + d[1] * v[1][2] + d[2] * v[2][2]
+                );
+            }
+        }
+    }
+
+    /// World-to-local rotation whose local z axis is a sampled axis, spun by a uniform angle around it.
+    pub fn sample_rotation(&self) -> [[f32; 3]; 3] {
+        let z = self.sample_axis();
+        let (u, v) = perpendicular_basis(&z);
+        let (s, c) = (2.0 * PI * rand::thread_rng().gen::<f32>()).sin_cos();
+        let x = Vector3Float::new(c * u.x + s * v.x, c * u.y + s * v.y, c * u.z + s * v.z);
+        let y = cross(&z, &x);
+        return [[x.x, x.y, x.z], [y.x, y.y, y.z], [z.x, z.y, z.z]];
+    }
+
+    /// Parameters of the distribution for the execution data.
+    pub fn describe(gen_data: &GeneratorData) -> String {
+        let p = &gen_data.orientation_parameters;
+        let a = &gen_data.orientation_axis;
+        match gen_data.orientation.as_str() {
+            "aligned" => format!("aligned to ({}, {}, {}), concentration {}", a.x, a.y, a.z, p[0]),
+            "planar" => format!("planar, normal ({}, {}, {})", a.x, a.y, a.z),
+            "tensor" => format!("tensor (a11 {}, a22 {}, a33 {}, a12 {}, a13 {}, a23 {})", p[0], p[1], p[2], p[3], p[4], p[5]),
+            _ => String::from("uniform")
+        }
+    }
+}
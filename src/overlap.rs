@@ -0,0 +1,49 @@
+use crate::instance::Instance;
+
+pub const OVERLAP_POLICIES: [&str; 6] = ["smallest", "largest", "order", "max-density", "add", "average"];
+
+/// How the renderers resolve voxels inside several instances, matches the OVERLAP_ modes in kernel.cl.
+pub const OVERLAP_FIRST: i32 = 0;
+pub const OVERLAP_MAX_DENSITY: i32 = 1;
+pub const OVERLAP_ADD: i32 = 2;
+pub const OVERLAP_AVERAGE: i32 = 3;
+
+/// Mode of the renderers for the policy. Without a policy, the first instance in the list wins.
+pub fn overlap_mode(policy: Option<&String>) -> i32 {
+    match policy.map(|p| p.as_str()) {
+        Some("max-density") => OVERLAP_MAX_DENSITY,
+        Some("add") => OVERLAP_ADD,
+        Some("average") => OVERLAP_AVERAGE,
+        _ => OVERLAP_FIRST
+    }
+}
+
+fn box_volume(instance: &Instance) -> f32 {
+    let bb = instance.bounding_box();
+    return (bb.0.1 - bb.0.0) * (bb.1.1 - bb.1.0) * (bb.2.1 - bb.2.0);
+}
+
+/// Orders the instances so the first one in the list containing a voxel is the one the policy picks:
+/// by bounding box volume for smallest and largest, by id for insertion order. Sorting is stable,
+/// so periodic images stay with their instance. Other policies keep the list as it is.
+pub fn order_instances(instances: &mut Vec<Instance>, policy: Option<&String>) {
+    match policy.map(|p| p.as_str()) {
+        Some("smallest") => instances.sort_by(|a, b| box_volume(a).total_cmp(&box_volume(b))),
+        Some("largest") => instances.sort_by(|a, b| box_volume(b).total_cmp(&box_volume(a))),
+        Some("order") => instances.sort_by_key(|i| i.id()),
+        _ => ()
+    }
+}
+
+/// Policy for the execution data.
+pub fn describe(policy: Option<&String>) -> String {
+    match policy.map(|p| p.as_str()) {
+        Some("smallest") => String::from("smallest instance wins"),
+        Some("largest") => String::from("largest instance wins"),
+        Some("order") => String::from("first inserted instance wins"),
+        Some("max-density") => String::from("densest instance wins"),
+        Some("add") => String::from("densities added, labelled by the first instance in the list"),
+        Some("average") => String::from("densities averaged, labelled by the first instance in the list"),
+        _ => String::from("first instance in the list wins (spheres are sorted smallest first)")
+    }
+}
@@ -0,0 +1,303 @@
+use std::f32::consts::PI;
+
+use rand::Rng;
+
+use crate::{GeneratorData, sphere::Sphere, vector3float::Vector3Float, container::Container, radius::RadiusDistribution};
+
+/// Target fractions above this are packed with force-biased growth instead of sequential addition.
+pub const DENSE_FRACTION: f32 = 0.3;
+
+const DENSE_ITERATIONS: usize = 20000;
+const DENSE_GROWTH: f32 = 1.002;
+/// Fraction the packing starts at before the spheres are grown.
+const DENSE_START_FRACTION: f32 = 0.1;
+
+/// Uniform grid over the volume for finding sphere neighbours.
+pub struct Grid {
+    cells_per_axis: [usize; 3],
+    extent: [f32; 3],
+    cells: Vec<Vec<usize>>
+}
+
+impl Grid {
+    pub fn new(min_cell_size: f32, extent: &[f32; 3]) -> Self {
+        let count = |size: f32| ((size / min_cell_size).floor() as usize).clamp(1, 128);
+        let cells_per_axis = [count(extent[0]), count(extent[1]), count(extent[2])];
+        return Self {
+            cells_per_axis,
+            extent: *extent,
+            cells: vec![Vec::new(); cells_per_axis[0] * cells_per_axis[1] * cells_per_axis[2]]
+        }
+    }
+
+    fn cell(&self, p: &[f32; 3]) -> [usize; 3] {
+        let n = self.cells_per_axis;
+        let c = |axis: usize| ((p[axis] / self.extent[axis] * n[axis] as f32).max(0.0) as usize).min(n[axis] - 1);
+        return [c(0), c(1), c(2)];
+    }
+
+    pub fn insert(&mut self, index: usize, p: &[f32; 3]) {
+        let [i, j, k] = self.cell(p);
+        let n = self.cells_per_axis;
+        self.cells[(k * n[1] + j) * n[0] + i].push(index);
+    }
+
+    /// Indices stored in the cell of the point and all cells around it,
+    /// which wrap around the faces in a periodic volume.
+    pub fn neighbours(&self, p: &[f32; 3], periodic: bool) -> Vec<usize> {
+        let [i, j, k] = self.cell(p);
+        let n = [self.cells_per_axis[0] as isize, self.cells_per_axis[1] as isize, self.cells_per_axis[2] as isize];
+        let mut visited = Vec::with_capacity(27);
+        let mut found = Vec::new();
+        for dk in -1..=1 {
+            for dj in -1..=1 {
+                for di in -1..=1 {
+                    let (mut ci, mut cj, mut ck) = (i as isize + di, j as isize + dj, k as isize + dk);
+                    if periodic {
+                        ci = ci.rem_euclid(n[0]);
+                        cj = cj.rem_euclid(n[1]);
+                        ck = ck.rem_euclid(n[2]);
+                    } else if ci < 0 || cj < 0 || ck < 0 || ci >= n[0] || cj >= n[1] || ck >= n[2] {
+                        continue;
+                    }
+                    let cell = ((ck * n[1] + cj) * n[0] + ci) as usize;
+                    if !visited.contains(&cell) {
+                        visited.push(cell);
+                        found.extend_from_slice(&self.cells[cell]);
+                    }
+                }
+            }
+        }
+        return found;
+    }
+}
+
+/// Extent of the volume the spheres are packed into, whether it wraps around its faces,
+/// and the space the volume fraction is measured in, the container interior if there is one.
+struct Volume {
+    extent: [f32; 3],
+    periodic: bool,
+    size: f32
+}
+
+impl Volume {
+    /// Random position of a sphere fully inside the volume, or anywhere in a periodic volume.
+    /// With a container, the sphere is placed inside it, or None if no position inside was found.
+    fn random_position(&self, r: f32, container: Option<&Container>) -> Option<[f32; 3]> {
+        let mut rand = rand::thread_rng();
+        if let Some(container) = container {
+            return container.random_position(r, 1000);
+        }
+        let mut p = [0.0; 3];
+        for (coordinate, size) in p.iter_mut().zip(self.extent.iter()) {
+            let margin = if self.periodic { 0.0 } else { r.min(size / 2.0) };
+            *coordinate = margin + rand.gen::<f32>() * (size - 2.0 * margin);
+        }
+        return Some(p);
+    }
+
+    /// Vector from b to a, between nearest periodic images in a periodic volume.
+    fn difference(&self, a: &[f32; 3], b: &[f32; 3]) -> [f32; 3] {
+        let mut d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+        if self.periodic {
+            for (component, size) in d.iter_mut().zip(self.extent.iter()) {
+                *component -= size * (*component / size).round();
+            }
+        }
+        return d;
+    }
+
+    fn distance(&self, a: &[f32; 3], b: &[f32; 3]) -> f32 {
+        let [x, y, z] = self.difference(a, b);
+        return (x*x + y*y + z*z).sqrt();
+    }
+
+    fn size(&self) -> f32 {
+        return self.size;
+    }
+}
+
+fn sphere_volume(r: f32) -> f32 {
+    return 4.0 / 3.0 * PI * r * r * r;
+}
+
+/// Random sequential addition, spheres are added at random free positions until the target
+/// is reached or a sphere cannot be placed in the given number of attempts.
+fn random_sequential_addition(gen_data: &GeneratorData, radii_distribution: &RadiusDistribution, target: f32, gap: f32, volume: &Volume, container: Option<&Container>, report: &mut Vec<String>) -> (Vec<[f32; 3]>, Vec<f32>) {
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut radii: Vec<f32> = Vec::new();
+    let mut grid = Grid::new(2.0 * radii_distribution.max_radius() + gap, &volume.extent);
+    let mut total = 0.0;
+
+    while total / volume.size() < target {
+        let r = radii_distribution.sample();
+        let mut placed = false;
+
+        for _ in 0..gen_data.placement_attempts {
+            let p = match volume.random_position(r, container) {
+                Some(p) => p,
+                None => {
+                    continue;
+                }
+            };
+            let free = grid.neighbours(&p, volume.periodic).iter().all(|&n| volume.distance(&p, &positions[n]) >= r + radii[n] + gap);
+            if free {
+                grid.insert(positions.len(), &p);
+                positions.push(p);
+                radii.push(r);
+                total += sphere_volume(r);
+                placed = true;
+                break;
+            }
+        }
+
+        if !placed {
+            println!("Warning: Random sequential addition stopped at volume fraction {}, try a dense packing", total / volume.size());
+            report.push(format!("Random sequential addition stopped early, sphere volume fraction {}", total / volume.size()));
+            break;
+        }
+    }
+
+    return (positions, radii);
+}
+
+/// Force-biased growth: spheres start small at random positions, and are slowly grown
+/// to their full size while overlapping pairs are pushed apart and kept inside the volume
+/// or wrapped around its faces.
+fn force_biased_packing(radii_distribution: &RadiusDistribution, target: f32, gap: f32, volume: &Volume, container: Option<&Container>, report: &mut Vec<String>) -> (Vec<[f32; 3]>, Vec<f32>) {
+    let mut radii: Vec<f32> = Vec::new();
+    let mut total = 0.0;
+    while total / volume.size() < target {
+        let r = radii_distribution.sample();
+        total += sphere_volume(r);
+        radii.push(r);
+    }
+
+    let max_radius = radii_distribution.max_radius();
+    let mut scale = (DENSE_START_FRACTION / target).cbrt().min(1.0);
+    // In a container, spheres start where they fit at their full size, and spheres too large for it are left out
+    let mut positions: Vec<[f32; 3]> = Vec::with_capacity(radii.len());
+    let mut kept: Vec<f32> = Vec::with_capacity(radii.len());
+    for r in radii.iter() {
+        let start = if container.is_some() { *r } else { r * scale };
+        if let Some(p) = volume.random_position(start, container) {
+            positions.push(p);
+            kept.push(*r);
+        }
+    }
+    if kept.len() < radii.len() {
+        println!("Warning: {} of {} spheres did not fit in the container and were left out of the dense packing", radii.len() - kept.len(), radii.len());
+        report.push(format!("Spheres left out of the dense packing for not fitting in the container: {}", radii.len() - kept.len()));
+    }
+    let radii = kept;
+    if radii.is_empty() {
+        return (positions, radii);
+    }
+    let mean_radius = radii.iter().sum::<f32>() / radii.len() as f32;
+    let tolerance = 0.001 * mean_radius;
+    let mut converged = false;
+
+    for _ in 0..DENSE_ITERATIONS {
+        let mut grid = Grid::new(2.0 * max_radius * scale + gap, &volume.extent);
+        for (index, p) in positions.iter().enumerate() {
+            grid.insert(index, p);
+        }
+
+        let mut displacements = vec![[0.0f32; 3]; positions.len()];
+        let mut worst_overlap = 0.0f32;
+        for a in 0..positions.len() {
+            for b in grid.neighbours(&positions[a], volume.periodic) {
+                if b <= a {
+                    continue;
+                }
+                let v = volume.difference(&positions[a], &positions[b]);
+                let d = (v[0]*v[0] + v[1]*v[1] + v[2]*v[2]).sqrt();
+                let overlap = (radii[a] + radii[b]) * scale + gap - d;
+                if overlap <= 0.0 {
+                    continue;
+                }
+                worst_overlap = worst_overlap.max(overlap);
+
+                // Push both spheres apart by half of the overlap, along x for coincident centers
+                let direction = if d > 0.0 {
+                    [v[0] / d, v[1] / d, v[2] / d]
+                } else {
+                    [1.0, 0.0, 0.0]
+                };
+                for axis in 0..3 {
+                    displacements[a][axis] += direction[axis] * overlap / 2.0;
+                    displacements[b][axis] -= direction[axis] * overlap / 2.0;
+                }
+            }
+        }
+
+        for (index, p) in positions.iter_mut().enumerate() {
+            let r = radii[index] * scale;
+            for axis in 0..3 {
+                let size = volume.extent[axis];
+                p[axis] = if volume.periodic {
+                    (p[axis] + displacements[index][axis]).rem_euclid(size)
+                } else {
+                    (p[axis] + displacements[index][axis]).clamp(r.min(size / 2.0), size - r.min(size / 2.0))
+                };
+            }
+            if let Some(container) = container {
+                container.constrain(p, r);
+            }
+        }
+
+        if worst_overlap <= tolerance {
+            if scale >= 1.0 {
+                converged = true;
+                break;
+            }
+            scale = (scale * DENSE_GROWTH).min(1.0);
+        }
+    }
+
+    if !converged {
+        println!("Warning: Dense packing did not converge, some spheres still overlap (reached {} of the full size)", scale);
+        report.push(format!("Dense packing did not converge, sphere size reached {} of the full size", scale));
+    }
+
+    return (positions, radii);
+}
+
+/// Places spheres until their volume reaches the target fraction. Spheres are kept fully
+/// inside the volume, unless it is periodic, and inside the container if there is one,
+/// in which case the fraction is of the container interior.
+pub fn pack_spheres(gen_data: &GeneratorData, radii_distribution: &RadiusDistribution, target: f32, densities: &Vec<u32>, report: &mut Vec<String>) -> Vec<Sphere> {
+    let gap = gen_data.min_gap.unwrap_or(0.0);
+    let e = &gen_data.extent;
+    let container = Container::new(gen_data);
+    let volume = Volume {
+        extent: [e.x, e.y, e.z],
+        periodic: gen_data.boundary == "periodic",
+        size: match &container {
+            Some(c) => c.interior_volume(),
+            None => e.x * e.y * e.z
+        }
+    };
+    let dense = match gen_data.packing.as_str() {
+        "rsa" => false,
+        "dense" => true,
+        _ => target > DENSE_FRACTION
+    };
+
+    let (positions, radii) = if dense {
+        force_biased_packing(radii_distribution, target, gap, &volume, container.as_ref(), report)
+    } else {
+        random_sequential_addition(gen_data, radii_distribution, target, gap, &volume, container.as_ref(), report)
+    };
+
+    let mut spheres = Vec::with_capacity(positions.len());
+    for (index, (p, r)) in positions.iter().zip(radii.iter()).enumerate() {
+        let density = densities[index % densities.len()];
+        spheres.push(Sphere::new(index as u32 + 1, *r, Vector3Float::new(p[0], p[1], p[2]), density));
+    }
+    spheres.sort();
+
+    report.push(format!("Packing: {}", if dense { "force-biased growth" } else { "random sequential addition" }));
+    println!("Packed {} spheres", spheres.len());
+    return spheres;
+}
@@ -0,0 +1,153 @@
+use crate::{csg::{CsgNode, Primitive, Transform}, instance::Instance, vector3float::Vector3Float, vector3uint::Vector3Usize};
+
+pub const PATTERN_NAMES: [&str; 4] = ["bars", "star", "edge", "points"];
+
+/// Widths of the bars in each line-pair group, in voxels along the x axis.
+const BAR_WIDTHS: [f32; 7] = [4.0, 3.0, 2.0, 1.5, 1.0, 0.75, 0.5];
+const BARS_PER_GROUP: usize = 3;
+
+const STAR_SPOKES: usize = 16;
+const STAR_RADIUS: f32 = 0.4;
+
+/// Angles of the slanted edge around the z and y axis, in degrees.
+const EDGE_ANGLES: (f32, f32) = (5.0, 3.0);
+
+/// Points are placed on a 4x4x4 grid, each shifted by a different fraction of a voxel.
+const POINT_GRID: usize = 4;
+const POINT_DENSITY: u32 = 255;
+const PATTERN_DENSITY: u32 = 200;
+
+fn cuboid(origin: Vector3Float, size: Vector3Float, angles: Vector3Float) -> CsgNode {
+    let half_extents = Vector3Float::new(size.x / 2.0, size.y / 2.0, size.z / 2.0);
+    return CsgNode::Primitive(Primitive::Cuboid { half_extents }, Transform::with_euler_angles(origin, &angles));
+}
+
+/// Groups of bars with the bar width equal to the gap, one group per instance, stacked along y.
+fn bars(dims: &Vector3Usize, extent: &Vector3Float) -> Vec<Instance> {
+    let voxel = extent.x / dims.x as f32;
+    let group_height = 0.8 * extent.y / BAR_WIDTHS.len() as f32;
+    let mut instances = Vec::new();
+
+    for (index, width) in BAR_WIDTHS.iter().enumerate() {
+        let w = width * voxel;
+        let group_width = w * (2 * BARS_PER_GROUP - 1) as f32;
+        let y = 0.1 * extent.y + group_height * (index as f32 + 0.5);
+
+        let mut bars = Vec::new();
+        for b in 0..BARS_PER_GROUP {
+            let x = extent.x / 2.0 - group_width / 2.0 + w * (2 * b) as f32 + w / 2.0;
+            let size = Vector3Float::new(w, group_height * 0.8, extent.z / 2.0);
+            bars.push(cuboid(Vector3Float::new(x, y, extent.z / 2.0), size, Vector3Float::new(0.0, 0.0, 0.0)));
+        }
+        instances.push(Instance::new(index as u32 + 1, PATTERN_DENSITY, CsgNode::Union(bars)));
+    }
+    return instances;
+}
+
+/// Siemens star extruded along z, every spoke is a sector of a cylinder cut by two half-spaces.
+fn star(extent: &Vector3Float) -> Vec<Instance> {
+    let sector = 360.0 / (2 * STAR_SPOKES) as f32;
+    let center = Vector3Float::new(extent.x / 2.0, extent.y / 2.0, extent.z / 2.0);
+    let size = largest(extent);
+    let mut spokes = Vec::new();
+
+    for s in 0..STAR_SPOKES {
+        let start = (2 * s) as f32 * sector;
+        let end = start + sector;
+        let cylinder = CsgNode::Primitive(
+            Primitive::Cylinder { radius: STAR_RADIUS * extent.x.min(extent.y), half_height: 0.3 * extent.z },
+            Transform::new(center.clone())
+        );
+        spokes.push(CsgNode::Intersection(vec![cylinder, half_space(&center, start + 90.0, size), half_space(&center, end - 90.0, size)]));
+    }
+
+    return vec![Instance::new(1, PATTERN_DENSITY, CsgNode::Union(spokes))];
+}
+
+fn largest(extent: &Vector3Float) -> f32 {
+    return extent.x.max(extent.y).max(extent.z);
+}
+
+/// Box large enough to act as the half-space on the side of the given direction in the xy plane,
+/// in a volume whose largest extent is the given size.
+fn half_space(point: &Vector3Float, angle: f32, size: f32) -> CsgNode {
+    let (s, c) = angle.to_radians().sin_cos();
+    let origin = Vector3Float::new(point.x + c * size, point.y + s * size, point.z);
+    return cuboid(origin, Vector3Float::new(2.0 * size, 4.0 * size, 4.0 * size), Vector3Float::new(0.0, 0.0, angle));
+}
+
+/// Half of the volume filled, with the edge slightly slanted against the y and z axis.
+fn edge(extent: &Vector3Float) -> Vec<Instance> {
+    let size = largest(extent);
+    let (around_z, around_y) = EDGE_ANGLES;
+    let angles = Vector3Float::new(0.0, around_y, around_z);
+    let (sz, cz) = around_z.to_radians().sin_cos();
+    let (sy, cy) = around_y.to_radians().sin_cos();
+
+    // Move the box back along its rotated x axis, so its face passes through the center
+    let origin = Vector3Float::new(extent.x / 2.0 - cy*cz * size, extent.y / 2.0 - cy*sz * size, extent.z / 2.0 + sy * size);
+    let box_size = Vector3Float::new(2.0 * size, 4.0 * size, 4.0 * size);
+    return vec![Instance::new(1, PATTERN_DENSITY, cuboid(origin, box_size, angles))];
+}
+
+/// Spheres with a radius of one voxel, shifted from voxel centers by multiples of a quarter voxel.
+fn points(dims: &Vector3Usize, extent: &Vector3Float) -> Vec<Instance> {
+    let voxel = Vector3Float::new(extent.x / dims.x as f32, extent.y / dims.y as f32, extent.z / dims.z as f32);
+    let radius = voxel.x.min(voxel.y).min(voxel.z);
+    let mut instances = Vec::new();
+
+    for k in 0..POINT_GRID {
+        for j in 0..POINT_GRID {
+            for i in 0..POINT_GRID {
+                let id = (instances.len() + 1) as u32;
+                let shift = 1.0 / POINT_GRID as f32;
+
+                // Snap to a voxel center, then move by the sub-voxel offset
+                let position = |index: usize, offset: usize, size: usize, voxel: f32| {
+                    let center = ((index as f32 + 0.5) / POINT_GRID as f32 * size as f32).floor() + 0.5;
+                    return (center + offset as f32 * shift) * voxel;
+                };
+                let origin = Vector3Float::new(
+                    position(i, i, dims.x, voxel.x),
+                    position(j, j, dims.y, voxel.y),
+                    position(k, k, dims.z, voxel.z)
+                );
+                let shape = CsgNode::Primitive(Primitive::Sphere { radius }, Transform::new(origin));
+                instances.push(Instance::new(id, POINT_DENSITY, shape));
+            }
+        }
+    }
+    return instances;
+}
+
+pub fn generate_pattern(name: &str, dims: &Vector3Usize, extent: &Vector3Float) -> Vec<Instance> {
+    let instances: Vec<Instance> = match name {
+        "bars" => bars(dims, extent),
+        "star" => star(extent),
+        "edge" => edge(extent),
+        _ => points(dims, extent)
+    };
+
+    let instances = instances.into_iter().map(|i| i.with_class(name)).collect::<Vec<Instance>>();
+    println!("Generated {} pattern with {} instances", name, instances.len());
+    return instances;
+}
+
+/// Summary of the pattern geometry for the execution data. Exact shapes are written to the instance data.
+pub fn describe_pattern(name: &str) -> String {
+    match name {
+        "bars" => {
+            let widths: Vec<String> = BAR_WIDTHS.iter().map(|w| w.to_string()).collect();
+            return format!("line pairs, {} bars per group, bar widths in x voxels: {}", BARS_PER_GROUP, widths.join(", "));
+        },
+        "star" => {
+            return format!("Siemens star along z, {} spokes, radius {} of the smaller x and y extent", STAR_SPOKES, STAR_RADIUS);
+        },
+        "edge" => {
+            return format!("slanted edge, angles {} deg around z and {} deg around y", EDGE_ANGLES.0, EDGE_ANGLES.1);
+        },
+        _ => {
+            return format!("{}x{}x{} point targets with a radius of one voxel, shifted by 1/{} voxel steps", POINT_GRID, POINT_GRID, POINT_GRID, POINT_GRID);
+        }
+    }
+}
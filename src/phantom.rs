@@ -0,0 +1,153 @@
+use std::f32::consts::PI;
+
+use crate::{csg::{CsgNode, Primitive, Transform}, instance::Instance, vector3float::Vector3Float};
+
+pub const PHANTOM_NAMES: [&str; 5] = ["shepp-logan", "modified-shepp-logan", "spheres", "contrast", "uniform"];
+
+/// Ellipsoids of the 3D Shepp-Logan phantom in the [-1, 1] cube, as used by
+/// phantom3d: axes (a, b, c), center (x, y, z) and Euler angles (phi, theta, psi) in degrees.
+const SHEPP_LOGAN_ELLIPSOIDS: [[f32; 9]; 10] = [
+    [0.6900, 0.920, 0.810, 0.00, 0.0000, 0.00, 0.0, 0.0, 0.0],
+    [0.6624, 0.874, 0.780, 0.00, -0.0184, 0.00, 0.0, 0.0, 0.0],
+    [0.1100, 0.310, 0.220, 0.22, 0.0000, 0.00, -18.0, 0.0, 10.0],
+    [0.1600, 0.410, 0.280, -0.22, 0.0000, 0.00, 18.0, 0.0, 10.0],
+    [0.2100, 0.250, 0.410, 0.00, 0.3500, -0.15, 0.0, 0.0, 0.0],
+    [0.0460, 0.046, 0.050, 0.00, 0.1000, 0.25, 0.0, 0.0, 0.0],
+    [0.0460, 0.046, 0.050, 0.00, -0.1000, 0.25, 0.0, 0.0, 0.0],
+    [0.0460, 0.023, 0.050, -0.08, -0.6050, 0.00, 0.0, 0.0, 0.0],
+    [0.0230, 0.023, 0.020, 0.00, -0.6060, 0.00, 0.0, 0.0, 0.0],
+    [0.0230, 0.046, 0.020, 0.06, -0.6050, 0.00, 0.0, 0.0, 0.0]
+];
+
+/// Additive intensities of the original phantom and the higher contrast modified one.
+const SHEPP_LOGAN_INTENSITIES: [f32; 10] = [2.0, -0.98, -0.02, -0.02, 0.01, 0.01, 0.01, 0.01, 0.01, 0.01];
+const MODIFIED_SHEPP_LOGAN_INTENSITIES: [f32; 10] = [1.0, -0.8, -0.2, -0.2, 0.1, 0.1, 0.1, 0.1, 0.1, 0.1];
+
+/// Intensity windows mapped onto densities 0 to 255. The original phantom uses the usual [1.0, 1.05]
+/// display window, so the brain structures keep their contrast and only the skull saturates.
+const SHEPP_LOGAN_WINDOW: (f32, f32) = (1.0, 1.05);
+const MODIFIED_SHEPP_LOGAN_WINDOW: (f32, f32) = (0.0, 1.0);
+
+/// Maps the unit cube the phantoms are designed in to a cube centered in the volume,
+/// with the edge of the smallest extent.
+struct Frame {
+    scale: f32,
+    offset: Vector3Float
+}
+
+impl Frame {
+    fn new(extent: &Vector3Float) -> Self {
+        let scale = extent.x.min(extent.y).min(extent.z);
+        let offset = Vector3Float::new((extent.x - scale) / 2.0, (extent.y - scale) / 2.0, (extent.z - scale) / 2.0);
+        return Self { scale, offset };
+    }
+
+    fn point(&self, x: f32, y: f32, z: f32) -> Vector3Float {
+        return Vector3Float::new(self.offset.x + x * self.scale, self.offset.y + y * self.scale, self.offset.z + z * self.scale);
+    }
+}
+
+fn euler_rotation(phi: f32, theta: f32, psi: f32) -> [[f32; 3]; 3] {
+    let (sphi, cphi) = phi.to_radians().sin_cos();
+    let (stheta, ctheta) = theta.to_radians().sin_cos();
+    let (spsi, cpsi) = psi.to_radians().sin_cos();
+
+    return [
+        [cpsi*cphi - ctheta*sphi*spsi, cpsi*sphi + ctheta*cphi*spsi, spsi*stheta],
+        [-spsi*cphi - ctheta*sphi*cpsi, -spsi*sphi + ctheta*cphi*cpsi, cpsi*stheta],
+        [stheta*sphi, -stheta*cphi, ctheta]
+    ];
+}
+
+fn shepp_logan(intensities: &[f32; 10], window: (f32, f32), frame: &Frame) -> Vec<Instance> {
+    let mut instances = Vec::new();
+
+    for (index, e) in SHEPP_LOGAN_ELLIPSOIDS.iter().enumerate() {
+        let rotation = euler_rotation(e[6], e[7], e[8]);
+
+        // phantom3d gives centers in the rotated frame, the cube is mapped from [-1, 1] to [0, 1]
+        let c = [e[3], e[4], e[5]];
+        let origin = frame.point(
+            0.5 + 0.5 * (rotation[0][0]*c[0] + rotation[1][0]*c[1] + rotation[2][0]*c[2]),
+            0.5 + 0.5 * (rotation[0][1]*c[0] + rotation[1][1]*c[1] + rotation[2][1]*c[2]),
+            0.5 + 0.5 * (rotation[0][2]*c[0] + rotation[1][2]*c[1] + rotation[2][2]*c[2])
+        );
+        let s = 0.5 * frame.scale;
+        let radii = Vector3Float::new(s * e[0], s * e[1], s * e[2]);
+        let shape = CsgNode::Primitive(Primitive::Ellipsoid { radii }, Transform::with_rotation(origin, rotation));
+
+        // The skull contains the brain, which contains all the other ellipsoids,
+        // so the inner regions are the sum of their ancestors' intensities
+        let intensity = match index {
+            0 => intensities[0],
+            1 => intensities[0] + intensities[1],
+            _ => intensities[0] + intensities[1] + intensities[index]
+        };
+        let density = ((intensity - window.0) / (window.1 - window.0) * 255.0).round().clamp(0.0, 255.0) as u32;
+
+        instances.push(Instance::new(index as u32 + 1, density, shape));
+    }
+
+    // Inner ellipsoids have to be found before the ones containing them
+    instances.reverse();
+    return instances;
+}
+
+/// Spheres of growing size along the x axis.
+fn spheres(frame: &Frame) -> Vec<Instance> {
+    let radii = [0.01, 0.02, 0.04, 0.06, 0.08];
+    let mut instances = Vec::new();
+    let mut x = 0.1;
+
+    for (index, radius) in radii.iter().enumerate() {
+        x += radius;
+        let shape = CsgNode::Primitive(Primitive::Sphere { radius: radius * frame.scale }, Transform::new(frame.point(x, 0.5, 0.5)));
+        instances.push(Instance::new(index as u32 + 1, 200, shape));
+        x += radius + 0.05;
+    }
+    return instances;
+}
+
+fn cylinder_body(frame: &Frame) -> Primitive {
+    return Primitive::Cylinder { radius: 0.4 * frame.scale, half_height: 0.4 * frame.scale };
+}
+
+/// Cylinder with inserts of different densities placed on a circle around its axis.
+fn contrast(frame: &Frame) -> Vec<Instance> {
+    let densities = [40, 80, 120, 160, 200, 240];
+    let mut instances = Vec::new();
+
+    for (index, density) in densities.iter().enumerate() {
+        let angle = 2.0 * PI * index as f32 / densities.len() as f32;
+        let origin = frame.point(0.5 + 0.25 * angle.cos(), 0.5 + 0.25 * angle.sin(), 0.5);
+        let insert = Primitive::Cylinder { radius: 0.06 * frame.scale, half_height: 0.4 * frame.scale };
+        let shape = CsgNode::Primitive(insert, Transform::new(origin));
+        instances.push(Instance::new(index as u32 + 2, *density, shape));
+    }
+
+    let body = CsgNode::Primitive(cylinder_body(frame), Transform::new(frame.point(0.5, 0.5, 0.5)));
+    instances.push(Instance::new(1, 100, body));
+    return instances;
+}
+
+/// Single cylinder of constant density for checking uniformity.
+fn uniform(frame: &Frame) -> Vec<Instance> {
+    let body = CsgNode::Primitive(cylinder_body(frame), Transform::new(frame.point(0.5, 0.5, 0.5)));
+    return vec![Instance::new(1, 200, body)];
+}
+
+/// Builds the phantom in a cube of the smallest volume extent, centered in the volume.
+pub fn generate_phantom(name: &str, extent: &Vector3Float) -> Vec<Instance> {
+    let frame = Frame::new(extent);
+    let instances: Vec<Instance> = match name {
+        "shepp-logan" => shepp_logan(&SHEPP_LOGAN_INTENSITIES, SHEPP_LOGAN_WINDOW, &frame),
+        "modified-shepp-logan" => shepp_logan(&MODIFIED_SHEPP_LOGAN_INTENSITIES, MODIFIED_SHEPP_LOGAN_WINDOW, &frame),
+        "spheres" => spheres(&frame),
+        "contrast" => contrast(&frame),
+        _ => uniform(&frame)
+    };
+
+    let instances = instances.into_iter().map(|i| i.with_class("phantom")).collect::<Vec<Instance>>();
+    println!("Generated {} phantom with {} instances", name, instances.len());
+    return instances;
+}
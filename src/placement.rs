@@ -0,0 +1,215 @@
+use std::cell::Cell;
+use std::f32::consts::PI;
+
+use std::io::Error;
+
+use rand::Rng;
+
+use crate::{GeneratorData, sphere::Sphere, vector3float::Vector3Float, intensity::IntensityField, container::Container};
+
+pub const PROCESS_NAMES: [&str; 5] = ["poisson", "thomas", "matern", "strauss", "lattice"];
+
+/// Number of candidates drawn before a point outside the volume or an inhibited point is accepted anyway.
+pub const MAX_TRIES: usize = 1000;
+
+/// Spatial point process that the origins of instances are drawn from,
+/// thinned by the placement intensity field.
+pub struct PointProcess {
+    kind: String,
+    intensity: IntensityField,
+    container: Option<Container>,
+    parents: Vec<Vector3Float>,
+    spread: f32,
+    interaction_radius: f32,
+    interaction_strength: f32,
+    lattice_size: usize,
+    jitter: f32,
+    periodic: bool,
+    extent: Vector3Float,
+    fallbacks: Cell<usize>
+}
+
+/// Uniform point in the volume, keeping the margin from the faces where the volume is large enough.
+fn uniform_point(margin: f32, extent: &Vector3Float) -> Vector3Float {
+    let mut rand = rand::thread_rng();
+    let mut coordinate = |size: f32| {
+        let m = margin.min(size / 2.0);
+        return m + rand.gen::<f32>() * (size - 2.0 * m);
+    };
+    return Vector3Float::new(coordinate(extent.x), coordinate(extent.y), coordinate(extent.z));
+}
+
+/// Standard normal sample by the Box-Muller transform, shared by all modules drawing normal values.
+pub(crate) fn normal_sample(rand: &mut impl Rng) -> f32 {
+    let u1 = 1.0 - rand.gen::<f32>();
+    let u2 = rand.gen::<f32>();
+    return (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos();
+}
+
+/// Uniformly distributed point in a ball of the given radius around the origin.
+fn uniform_in_ball(radius: f32) -> Vector3Float {
+    let mut rand = rand::thread_rng();
+    loop {
+        let p = Vector3Float::new(rand.gen::<f32>() * 2.0 - 1.0, rand.gen::<f32>() * 2.0 - 1.0, rand.gen::<f32>() * 2.0 - 1.0);
+        if p.length() <= 1.0 {
+            return Vector3Float::new(p.x * radius, p.y * radius, p.z * radius);
+        }
+    }
+}
+
+impl PointProcess {
+    /// Creates the process for one volume, cluster parents are sampled here.
+    pub fn new(gen_data: &GeneratorData) -> Result<Self, Error> {
+        let intensity = IntensityField::load(gen_data)?;
+        let extent = gen_data.extent.clone();
+        let parents = match gen_data.process.as_str() {
+            "thomas" | "matern" => (0..gen_data.cluster_count.max(1)).map(|_| uniform_point(0.0, &extent)).collect(),
+            _ => Vec::new()
+        };
+
+        return Ok(Self {
+            kind: gen_data.process.clone(),
+            intensity,
+            container: Container::new(gen_data),
+            parents,
+            spread: gen_data.cluster_spread,
+            interaction_radius: gen_data.interaction_radius,
+            interaction_strength: gen_data.interaction_strength,
+            lattice_size: (gen_data.sphere_count as f32).cbrt().ceil().max(1.0) as usize,
+            jitter: gen_data.lattice_jitter,
+            periodic: gen_data.boundary == "periodic",
+            extent,
+            fallbacks: Cell::new(0)
+        });
+    }
+
+    /// Wraps the point into a periodic volume, or checks that it keeps the margin from the faces.
+    fn fit(&self, p: Vector3Float, margin: f32) -> Option<Vector3Float> {
+        let e = &self.extent;
+        if self.periodic {
+            return Some(Vector3Float::new(p.x.rem_euclid(e.x), p.y.rem_euclid(e.y), p.z.rem_euclid(e.z)));
+        }
+        let inside = |v: f32, size: f32| {
+            let m = margin.min(size / 2.0);
+            return v >= m && v <= size - m;
+        };
+        if inside(p.x, e.x) && inside(p.y, e.y) && inside(p.z, e.z) {
+            return Some(p);
+        }
+        return None;
+    }
+
+    fn distance(&self, a: &Vector3Float, b: &Vector3Float) -> f32 {
+        let mut d = Vector3Float::sub(a, b);
+        if self.periodic {
+            let e = &self.extent;
+            d = Vector3Float::new(d.x - e.x * (d.x / e.x).round(), d.y - e.y * (d.y / e.y).round(), d.z - e.z * (d.z / e.z).round());
+        }
+        return d.length();
+    }
+
+    fn candidate(&self, placed: &[Sphere]) -> Vector3Float {
+        let mut rand = rand::thread_rng();
+        match self.kind.as_str() {
+            "thomas" => {
+                let parent = &self.parents[rand.gen_range(0..self.parents.len())];
+                let s = self.spread;
+                return Vector3Float::new(parent.x + s * normal_sample(&mut rand), parent.y + s * normal_sample(&mut rand), parent.z + s * normal_sample(&mut rand));
+            },
+            "matern" => {
+                let parent = &self.parents[rand.gen_range(0..self.parents.len())];
+                let offset = uniform_in_ball(self.spread);
+                return Vector3Float::new(parent.x + offset.x, parent.y + offset.y, parent.z + offset.z);
+            },
+            "lattice" => {
+                // Sites are filled in order, jitter is a fraction of the lattice spacing
+                let n = self.lattice_size;
+                let site = placed.len() % (n * n * n);
+                let e = &self.extent;
+                let coordinate = |index: usize, size: f32| (index as f32 + 0.5 + self.jitter * (rand::random::<f32>() - 0.5)) * size / n as f32;
+                return Vector3Float::new(coordinate(site % n, e.x), coordinate((site / n) % n, e.y), coordinate(site / (n * n), e.z));
+            },
+            _ => {
+                return uniform_point(0.0, &self.extent);
+            }
+        }
+    }
+
+    /// Samples the origin of the next sphere of radius r, given the spheres placed so far.
+    /// Spheres are kept inside the container, and inside the volume if requested.
+    pub fn sample(&self, placed: &[Sphere], r: f32, inside: bool) -> Vector3Float {
+        let mut rand = rand::thread_rng();
+        let margin = if inside { r } else { 0.0 };
+
+        for _ in 0..MAX_TRIES {
+            let p = if self.kind == "poisson" {
+                uniform_point(margin, &self.extent)
+            } else {
+                match self.fit(self.candidate(placed), margin) {
+                    Some(p) => p,
+                    None => {
+                        continue;
+                    }
+                }
+            };
+
+            if !self.intensity.accepts(&p) {
+                continue;
+            }
+            if let Some(container) = &self.container {
+                if !container.fits(&p, r) {
+                    continue;
+                }
+            }
+
+            if self.kind == "strauss" {
+                // Sequential inhibition, every placed point within the interaction
+                // radius multiplies the acceptance probability by the strength
+                let close = placed.iter().filter(|s| self.distance(s.origin(), &p) < self.interaction_radius).count();
+                if rand.gen::<f32>() >= self.interaction_strength.powi(close as i32) {
+                    continue;
+                }
+            }
+            return p;
+        }
+
+        // No candidate was accepted, the point only keeps the container or the mask if there is one
+        self.fallbacks.set(self.fallbacks.get() + 1);
+        if let Some(container) = &self.container {
+            if let Some(p) = container.random_position(r, MAX_TRIES as u32) {
+                return Vector3Float::new(p[0], p[1], p[2]);
+            }
+        }
+        match self.intensity.fallback() {
+            Some(p) => {
+                return p;
+            },
+            None => {
+                return uniform_point(margin, &self.extent);
+            }
+        }
+    }
+
+    /// Number of origins drawn by the fallback after no candidate was accepted in MAX_TRIES tries.
+    pub fn fallback_count(&self) -> usize {
+        return self.fallbacks.get();
+    }
+
+    /// Parameters of the process for the execution data.
+    pub fn describe(gen_data: &GeneratorData) -> String {
+        let process = match gen_data.process.as_str() {
+            "thomas" => format!("thomas ({} clusters, standard deviation {})", gen_data.cluster_count, gen_data.cluster_spread),
+            "matern" => format!("matern ({} clusters, radius {})", gen_data.cluster_count, gen_data.cluster_spread),
+            "strauss" => format!("strauss (interaction radius {}, strength {})", gen_data.interaction_radius, gen_data.interaction_strength),
+            "lattice" => format!("lattice (jitter {} of the spacing)", gen_data.lattice_jitter),
+            _ => String::from("poisson")
+        };
+        if let Some(mask) = &gen_data.intensity_mask {
+            return format!("{}, thinned by mask {}", process, mask);
+        }
+        if let Some(expression) = &gen_data.intensity {
+            return format!("{}, thinned by intensity {}", process, expression);
+        }
+        return process;
+    }
+}
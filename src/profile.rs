@@ -0,0 +1,93 @@
+use rand::Rng;
+
+use crate::GeneratorData;
+
+pub const PROFILE_NAMES: [&str; 5] = ["flat", "linear", "gaussian", "core-shell", "halo"];
+
+/// Codes of the profiles in the instance buffer, matches the PROFILE_ constants in kernel.cl.
+const PROFILE_FLAT: f32 = 0.0;
+const PROFILE_LINEAR: f32 = 1.0;
+const PROFILE_GAUSSIAN: f32 = 2.0;
+const PROFILE_CORE_SHELL: f32 = 3.0;
+const PROFILE_HALO: f32 = 4.0;
+
+/// Number of parameters, each given as a range on the command line.
+pub fn parameter_count(name: &str) -> usize {
+    match name {
+        "linear" | "gaussian" => 1,
+        "core-shell" | "halo" => 2,
+        _ => 0
+    }
+}
+
+/// Density profile inside an instance, as a function of the relative radius s, which is 0 at
+/// the given depth below the surface (the center of a sphere) and 1 on the surface:
+/// * linear: density * (1 - falloff * s)
+/// * gaussian: density * exp(-s^2 / (2 * width^2))
+/// * core-shell: density for s below the core fraction, the shell density outside
+/// * halo: density * (1 + strength * exp(-((1 - s) / width)^2)), a bright or dark rim for positive or negative strength
+#[derive(Clone)]
+pub struct Profile {
+    pub kind: String,
+    pub parameters: [f32; 2],
+    pub depth: f32
+}
+
+impl Profile {
+    /// Samples the parameters of the profile from their ranges, None for flat instances.
+    pub fn sample(gen_data: &GeneratorData, depth: f32) -> Option<Self> {
+        if gen_data.profile == "flat" {
+            return None;
+        }
+        let mut rand = rand::thread_rng();
+        let r = &gen_data.profile_ranges;
+        let mut parameters = [0.0; 2];
+        for (index, p) in parameters.iter_mut().enumerate().take(parameter_count(&gen_data.profile)) {
+            let (min, max) = (r[2 * index], r[2 * index + 1]);
+            *p = min + rand.gen::<f32>() * (max - min);
+        }
+        return Some(Self {
+            kind: gen_data.profile.clone(),
+            parameters,
+            depth
+        });
+    }
+
+    pub fn code(&self) -> f32 {
+        match self.kind.as_str() {
+            "linear" => PROFILE_LINEAR,
+            "gaussian" => PROFILE_GAUSSIAN,
+            "core-shell" => PROFILE_CORE_SHELL,
+            "halo" => PROFILE_HALO,
+            _ => PROFILE_FLAT
+        }
+    }
+
+    /// Density at the signed distance d (negative inside) of an instance with the given density,
+    /// limited to the largest density of the output type. Matches profile_density in kernel.cl.
+    pub fn density(&self, density: f32, d: f32, max_density: f32) -> f32 {
+        let s = (1.0 + d / self.depth.max(f32::EPSILON)).clamp(0.0, 1.0);
+        let [a, b] = self.parameters;
+        let value = match self.kind.as_str() {
+            "linear" => density * (1.0 - a * s),
+            "gaussian" => density * (-s * s / (2.0 * a * a)).exp(),
+            "core-shell" => if s <= a { density } else { b },
+            "halo" => density * (1.0 + b * (-((1.0 - s) / a).powi(2)).exp()),
+            _ => density
+        };
+        return value.clamp(0.0, max_density);
+    }
+
+    /// Profile and parameter ranges for the execution data.
+    pub fn describe(gen_data: &GeneratorData) -> String {
+        let r = &gen_data.profile_ranges;
+        let range = |index: usize| format!("({}, {})", r[2 * index], r[2 * index + 1]);
+        match gen_data.profile.as_str() {
+            "linear" => format!("linear, falloff {}", range(0)),
+            "gaussian" => format!("gaussian, width {}", range(0)),
+            "core-shell" => format!("core-shell, core fraction {}, shell density {}", range(0), range(1)),
+            "halo" => format!("halo, width {}, strength {}", range(0), range(1)),
+            _ => String::from("flat")
+        }
+    }
+}
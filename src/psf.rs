@@ -0,0 +1,373 @@
+use std::{f32::consts::PI, fs, io::{Error, ErrorKind}};
+
+use crate::GeneratorData;
+
+pub const PSF_NAMES: [&str; 4] = ["gaussian", "airy", "widefield", "file"];
+
+/// Gaussian kernels are cut off at this many standard deviations.
+const KERNEL_RADIUS: f32 = 3.0;
+/// Airy patterns are cut off at their third dark ring, the third zero of J1.
+const AIRY_CUTOFF: f32 = 10.1735;
+/// Full width at half maximum of a Gaussian in standard deviations.
+const FWHM: f32 = 2.35482;
+/// Largest number of voxels per axis convolved at once, the FFT size adds the kernel overlap and is rounded up to a power of two.
+const BLOCK_SIZE: usize = 64;
+
+/// Number of parameters of the point spread function, the Gaussian also takes one sigma for all axes.
+pub fn parameter_count(name: &str) -> usize {
+    match name {
+        "gaussian" => 3,
+        "airy" => 2,
+        "widefield" | "file" => 3,
+        _ => 0
+    }
+}
+
+/// Discrete Gaussian kernel with the given standard deviation in voxels, normalised to a sum of 1.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    if sigma <= 0.0 {
+        return vec![1.0];
+    }
+    let radius = (KERNEL_RADIUS * sigma).ceil() as i32;
+    let weights: Vec<f32> = (-radius..=radius).map(|x| (-(x * x) as f32 / (2.0 * sigma * sigma)).exp()).collect();
+    let sum: f32 = weights.iter().sum();
+    return weights.into_iter().map(|w| w / sum).collect();
+}
+
+/// Position inside an axis of n voxels, wrapped around in a periodic volume and repeating the edge voxel otherwise.
+fn source_index(position: isize, n: usize, periodic: bool) -> usize {
+    if periodic {
+        return position.rem_euclid(n as isize) as usize;
+    }
+    return position.clamp(0, n as isize - 1) as usize;
+}
+
+/// Convolves the volume with a symmetric kernel along one axis.
+pub fn convolve_axis(volume: &[f32], dims: [usize; 3], axis: usize, kernel: &[f32], periodic: bool) -> Vec<f32> {
+    let radius = (kernel.len() / 2) as isize;
+    let stride = [1, dims[0], dims[0] * dims[1]][axis];
+    let mut result = vec![0.0; volume.len()];
+    for (index, value) in result.iter_mut().enumerate() {
+        let position = ((index / stride) % dims[axis]) as isize;
+        let start = index - position as usize * stride;
+        for (offset, weight) in kernel.iter().enumerate() {
+            let q = source_index(position + offset as isize - radius, dims[axis], periodic);
+            *value += weight * volume[start + q * stride];
+        }
+    }
+    return result;
+}
+
+/// J1 Bessel function, from the rational and asymptotic approximations in Numerical Recipes.
+fn bessel_j1(x: f32) -> f32 {
+    let x = x as f64;
+    let ax = x.abs();
+    if ax < 8.0 {
+        let y = x * x;
+        let numerator = x * (72362614232.0 + y * (-7895059235.0 + y * (242396853.1 + y * (-2972611.439 + y * (15704.48260 + y * -30.16036606)))));
+        let denominator = 144725228442.0 + y * (2300535178.0 + y * (18583304.74 + y * (99447.43394 + y * (376.9991397 + y))));
+        return (numerator / denominator) as f32;
+    }
+    let z = 8.0 / ax;
+    let y = z * z;
+    let shifted = ax - 2.356194491;
+    let p = 1.0 + y * (0.183105e-2 + y * (-0.3516396496e-4 + y * (0.2457520174e-5 + y * -0.240337019e-6)));
+    let q = 0.04687499995 + y * (-0.2002690873e-3 + y * (0.8449199096e-5 + y * (-0.88228987e-6 + y * 0.105787412e-6)));
+    let value = (std::f64::consts::FRAC_2_PI / ax).sqrt() * (shifted.cos() * p - z * shifted.sin() * q);
+    return (if x < 0.0 { -value } else { value }) as f32;
+}
+
+/// Intensity of the Airy pattern at the distance r from its center, 1 at the center.
+fn airy(r: f32, wavelength: f32, aperture: f32) -> f32 {
+    let v = 2.0 * PI * aperture * r / wavelength;
+    if v < 1e-6 {
+        return 1.0;
+    }
+    let a = 2.0 * bessel_j1(v) / v;
+    return a * a;
+}
+
+/// Sampled point spread function, x changes fastest. The center voxel is at the origin of the convolution.
+struct Kernel {
+    dims: [usize; 3],
+    center: [usize; 3],
+    values: Vec<f32>
+}
+
+impl Kernel {
+    /// Samples the function of the offset from the center in scene units at the voxel centers, up to the given reach.
+    fn sample(gen_data: &GeneratorData, reach: [f32; 3], f: impl Fn(f32, f32, f32) -> f32) -> Self {
+        let spacing = gen_data.spacing();
+        let h = [spacing.x, spacing.y, spacing.z];
+        let d = &gen_data.pixel_dimensions;
+        let n = [d.x, d.y, d.z];
+        let half: Vec<usize> = (0..3).map(|a| ((reach[a] / h[a]).floor() as usize).min(n[a].saturating_sub(1))).collect();
+        let dims = [2 * half[0] + 1, 2 * half[1] + 1, 2 * half[2] + 1];
+        let mut values = Vec::with_capacity(dims[0] * dims[1] * dims[2]);
+        for k in 0..dims[2] {
+            for j in 0..dims[1] {
+                for i in 0..dims[0] {
+                    let offset = |index: usize, axis: usize| (index as f32 - half[axis] as f32) * h[axis];
+                    values.push(f(offset(i, 0), offset(j, 1), offset(k, 2)));
+                }
+            }
+        }
+        return Self::normalized(dims, [half[0], half[1], half[2]], values);
+    }
+
+    fn normalized(dims: [usize; 3], center: [usize; 3], values: Vec<f32>) -> Self {
+        let sum: f32 = values.iter().sum();
+        return Self {
+            dims,
+            center,
+            values: values.into_iter().map(|v| v / sum).collect()
+        };
+    }
+
+    /// Reads a centered kernel of big-endian 32 bit floats, sampled with the voxel spacing of the volume.
+    fn load(filename: &str, dims: [usize; 3]) -> Result<Self, Error> {
+        let data = fs::read(filename)?;
+        let count = dims[0] * dims[1] * dims[2];
+        if data.len() != 4 * count {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("PSF {} has {} bytes, {}x{}x{} floats need {}", filename, data.len(), dims[0], dims[1], dims[2], 4 * count)));
+        }
+        let values: Vec<f32> = data.chunks_exact(4).map(|b| f32::from_be_bytes([b[0], b[1], b[2], b[3]])).collect();
+        if values.iter().any(|v| !v.is_finite()) || values.iter().sum::<f32>() <= 0.0 {
+            return Err(Error::new(ErrorKind::InvalidData, format!("PSF {} must be finite with a positive sum", filename)));
+        }
+        return Ok(Self::normalized(dims, [dims[0] / 2, dims[1] / 2, dims[2] / 2], values));
+    }
+
+    /// Largest distance of a kernel voxel from the center along each axis.
+    fn reach(&self) -> [usize; 3] {
+        let r = |a: usize| self.center[a].max(self.dims[a] - 1 - self.center[a]);
+        return [r(0), r(1), r(2)];
+    }
+}
+
+/// In-place radix-2 FFT of a line whose length is a power of two, inverse without the 1/n scaling.
+fn fft(re: &mut [f32], im: &mut [f32], inverse: bool) {
+    let n = re.len();
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut length = 2;
+    while length <= n {
+        let angle = sign * 2.0 * PI / length as f32;
+        for start in (0..n).step_by(length) {
+            for k in 0..length / 2 {
+                let (s, c) = (angle * k as f32).sin_cos();
+                let (a, b) = (start + k, start + k + length / 2);
+                let (tr, ti) = (re[b] * c - im[b] * s, re[b] * s + im[b] * c);
+                re[b] = re[a] - tr;
+                im[b] = im[a] - ti;
+                re[a] += tr;
+                im[a] += ti;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+/// FFT along every axis of a complex volume.
+fn fft_3d(re: &mut [f32], im: &mut [f32], dims: [usize; 3], inverse: bool) {
+    for axis in 0..3 {
+        let n = dims[axis];
+        let stride = [1, dims[0], dims[0] * dims[1]][axis];
+        let (mut line_re, mut line_im) = (vec![0.0; n], vec![0.0; n]);
+        for start in (0..re.len()).filter(|i| (i / stride).is_multiple_of(n)) {
+            for (t, (r, i)) in line_re.iter_mut().zip(line_im.iter_mut()).enumerate() {
+                *r = re[start + t * stride];
+                *i = im[start + t * stride];
+            }
+            fft(&mut line_re, &mut line_im, inverse);
+            for (t, (r, i)) in line_re.iter().zip(line_im.iter()).enumerate() {
+                re[start + t * stride] = *r;
+                im[start + t * stride] = *i;
+            }
+        }
+    }
+}
+
+/// Convolution by FFT in overlapping blocks (overlap-save), so memory stays bounded for large volumes.
+fn convolve_fft(volume: &[f32], dims: [usize; 3], kernel: &Kernel, periodic: bool) -> Vec<f32> {
+    let reach = kernel.reach();
+    let padded: Vec<usize> = (0..3).map(|a| (BLOCK_SIZE.min(dims[a]) + 2 * reach[a]).next_power_of_two()).collect();
+    let core: Vec<usize> = (0..3).map(|a| padded[a] - 2 * reach[a]).collect();
+    let p = [padded[0], padded[1], padded[2]];
+    let size = p[0] * p[1] * p[2];
+
+    // Spectrum of the kernel, wrapped around so its center is at the origin
+    let (mut kernel_re, mut kernel_im) = (vec![0.0; size], vec![0.0; size]);
+    for k in 0..kernel.dims[2] {
+        for j in 0..kernel.dims[1] {
+            for i in 0..kernel.dims[0] {
+                let wrap = |index: usize, axis: usize| (index as isize - kernel.center[axis] as isize).rem_euclid(p[axis] as isize) as usize;
+                let target = (wrap(k, 2) * p[1] + wrap(j, 1)) * p[0] + wrap(i, 0);
+                kernel_re[target] = kernel.values[(k * kernel.dims[1] + j) * kernel.dims[0] + i];
+            }
+        }
+    }
+    fft_3d(&mut kernel_re, &mut kernel_im, p, false);
+
+    let mut result = vec![0.0; volume.len()];
+    for oz in (0..dims[2]).step_by(core[2]) {
+        for oy in (0..dims[1]).step_by(core[1]) {
+            for ox in (0..dims[0]).step_by(core[0]) {
+                let origin = [ox, oy, oz];
+                let source = |b: usize, axis: usize| source_index(origin[axis] as isize - reach[axis] as isize + b as isize, dims[axis], periodic);
+                let (mut re, mut im) = (vec![0.0; size], vec![0.0; size]);
+                for bz in 0..p[2] {
+                    for by in 0..p[1] {
+                        let row = (source(bz, 2) * dims[1] + source(by, 1)) * dims[0];
+                        for bx in 0..p[0] {
+                            re[(bz * p[1] + by) * p[0] + bx] = volume[row + source(bx, 0)];
+                        }
+                    }
+                }
+
+                fft_3d(&mut re, &mut im, p, false);
+                for index in 0..size {
+                    let (a, b) = (re[index], im[index]);
+                    re[index] = a * kernel_re[index] - b * kernel_im[index];
+                    im[index] = a * kernel_im[index] + b * kernel_re[index];
+                }
+                fft_3d(&mut re, &mut im, p, true);
+
+                // Only the core of the block is free of wrapped-around contributions
+                for tz in 0..core[2].min(dims[2] - oz) {
+                    for ty in 0..core[1].min(dims[1] - oy) {
+                        for tx in 0..core[0].min(dims[0] - ox) {
+                            let b = ((tz + reach[2]) * p[1] + ty + reach[1]) * p[0] + tx + reach[0];
+                            result[((oz + tz) * dims[1] + oy + ty) * dims[0] + ox + tx] = re[b] / size as f32;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    return result;
+}
+
+/// Blurs the clean densities with the point spread function, before noise is added. Lengths are in scene units.
+/// Gaussians are separable and convolved directly, all other functions by FFT.
+pub fn apply_psf(gen_data: &GeneratorData, densities: &mut Vec<f32>) -> Result<(), Error> {
+    let d = &gen_data.pixel_dimensions;
+    let dims = [d.x, d.y, d.z];
+    let periodic = gen_data.boundary == "periodic";
+    let p = &gen_data.psf_parameters;
+    let kernel = match gen_data.psf.as_deref() {
+        Some("gaussian") => {
+            let spacing = gen_data.spacing();
+            let mut blurred = densities.clone();
+            for (axis, h) in [spacing.x, spacing.y, spacing.z].iter().enumerate() {
+                blurred = convolve_axis(&blurred, dims, axis, &gaussian_kernel(p[axis] / h), periodic);
+            }
+            *densities = blurred;
+            return Ok(());
+        },
+        Some("airy") => {
+            // In-plane only, every z slice is blurred on its own
+            let reach = AIRY_CUTOFF * p[0] / (2.0 * PI * p[1]);
+            Kernel::sample(gen_data, [reach, reach, 0.0], |x, y, _| airy((x * x + y * y).sqrt(), p[0], p[1]))
+        },
+        Some("widefield") => {
+            // Airy disc in every plane, with a Gaussian along z of the axial resolution 2 n wavelength / NA^2 as its FWHM
+            let reach = AIRY_CUTOFF * p[0] / (2.0 * PI * p[1]);
+            let sigma_z = 2.0 * p[2] * p[0] / (p[1] * p[1]) / FWHM;
+            Kernel::sample(gen_data, [reach, reach, KERNEL_RADIUS * sigma_z], |x, y, z| {
+                airy((x * x + y * y).sqrt(), p[0], p[1]) * (-z * z / (2.0 * sigma_z * sigma_z)).exp()
+            })
+        },
+        Some(_) => {
+            let filename = gen_data.psf_file.as_ref().unwrap();
+            Kernel::load(filename, [p[0] as usize, p[1] as usize, p[2] as usize])?
+        },
+        None => {
+            return Ok(());
+        }
+    };
+    println!("Convolving with a {}x{}x{} PSF", kernel.dims[0], kernel.dims[1], kernel.dims[2]);
+    *densities = convolve_fft(densities, dims, &kernel, periodic);
+    return Ok(());
+}
+
+/// Point spread function and parameters for the execution data.
+pub fn describe(gen_data: &GeneratorData) -> String {
+    let p = &gen_data.psf_parameters;
+    match gen_data.psf.as_deref() {
+        Some("gaussian") => format!("gaussian, sigma ({}, {}, {})", p[0], p[1], p[2]),
+        Some("airy") => format!("airy, wavelength {}, numerical aperture {}", p[0], p[1]),
+        Some("widefield") => format!("widefield, wavelength {}, numerical aperture {}, refractive index {}", p[0], p[1], p[2]),
+        Some(_) => format!("file {}, {}x{}x{} voxels", gen_data.psf_file.as_deref().unwrap_or(""), p[0], p[1], p[2]),
+        None => String::from("none")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Convolution by its definition, with the same boundary handling as the FFT.
+    fn convolve_direct(volume: &[f32], dims: [usize; 3], kernel: &Kernel, periodic: bool) -> Vec<f32> {
+        let mut result = vec![0.0; volume.len()];
+        for z in 0..dims[2] {
+            for y in 0..dims[1] {
+                for x in 0..dims[0] {
+                    let mut sum = 0.0;
+                    for k in 0..kernel.dims[2] {
+                        for j in 0..kernel.dims[1] {
+                            for i in 0..kernel.dims[0] {
+                                let position = [x, y, z];
+                                let offset = [i, j, k];
+                                let s = |axis: usize| source_index(position[axis] as isize - offset[axis] as isize + kernel.center[axis] as isize, dims[axis], periodic);
+                                sum += kernel.values[(k * kernel.dims[1] + j) * kernel.dims[0] + i] * volume[(s(2) * dims[1] + s(1)) * dims[0] + s(0)];
+                            }
+                        }
+                    }
+                    result[(z * dims[1] + y) * dims[0] + x] = sum;
+                }
+            }
+        }
+        return result;
+    }
+
+    #[test]
+    fn fft_convolution_matches_direct_convolution() {
+        // More than one block along x, and an asymmetric kernel that is not centered
+        let dims = [70, 9, 5];
+        let volume: Vec<f32> = (0..dims[0] * dims[1] * dims[2]).map(|i| ((i * 7919) % 101) as f32).collect();
+        let values: Vec<f32> = (0..3 * 5 * 2).map(|i| 1.0 + (i % 7) as f32).collect();
+        let kernel = Kernel::normalized([3, 5, 2], [0, 3, 1], values);
+
+        for periodic in [false, true] {
+            let fast = convolve_fft(&volume, dims, &kernel, periodic);
+            let direct = convolve_direct(&volume, dims, &kernel, periodic);
+            for (a, b) in fast.iter().zip(direct.iter()) {
+                assert!((a - b).abs() < 1e-3, "fft {} and direct {} differ, periodic {}", a, b, periodic);
+            }
+        }
+    }
+
+    #[test]
+    fn convolution_keeps_constant_volumes() {
+        let dims = [8, 8, 8];
+        let volume = vec![3.0; 8 * 8 * 8];
+        let kernel = Kernel::normalized([3, 3, 3], [1, 1, 1], (0..27).map(|i| i as f32).collect());
+        for v in convolve_fft(&volume, dims, &kernel, false) {
+            assert!((v - 3.0).abs() < 1e-4);
+        }
+    }
+}
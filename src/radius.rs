@@ -0,0 +1,217 @@
+use std::{fs, io::{Error, ErrorKind}};
+
+use rand::Rng;
+
+use crate::{GeneratorData, placement::normal_sample};
+
+pub const RADIUS_DISTRIBUTIONS: [&str; 7] = ["uniform", "normal", "lognormal", "gamma", "powerlaw", "discrete", "empirical"];
+
+/// Samples drawn from an unbounded distribution before it is clamped to the radius range.
+const MAX_TRIES: usize = 1000;
+
+/// Gamma distributed sample with the given shape and unit scale (Marsaglia and Tsang).
+fn gamma_sample(shape: f32) -> f32 {
+    let mut rand = rand::thread_rng();
+    if shape < 1.0 {
+        let u = 1.0 - rand.gen::<f32>();
+        return gamma_sample(shape + 1.0) * u.powf(1.0 / shape);
+    }
+
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = normal_sample(&mut rand);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u = 1.0 - rand.gen::<f32>();
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+/// Picks an index with a probability proportional to its weight.
+fn weighted_index(weights: &Vec<f32>) -> usize {
+    let total: f32 = weights.iter().sum();
+    let mut x = rand::thread_rng().gen::<f32>() * total;
+    for (index, w) in weights.iter().enumerate() {
+        if x < *w {
+            return index;
+        }
+        x -= w;
+    }
+    return weights.len() - 1;
+}
+
+fn parameter_error(message: String) -> Error {
+    return Error::new(ErrorKind::InvalidInput, message);
+}
+
+/// Distribution of sphere radii. Continuous distributions are truncated to the -r range,
+/// discrete sets and histograms give their radii directly.
+pub enum RadiusDistribution {
+    Uniform { min: f32, max: f32 },
+    Normal { mean: f32, deviation: f32, min: f32, max: f32 },
+    LogNormal { median: f32, sigma: f32, min: f32, max: f32 },
+    Gamma { shape: f32, scale: f32, min: f32, max: f32 },
+    PowerLaw { exponent: f32, min: f32, max: f32 },
+    Discrete { radii: Vec<f32>, weights: Vec<f32> },
+    /// Bins given by their lower and upper radius
+    Empirical { bins: Vec<(f32, f32)>, weights: Vec<f32> }
+}
+
+/// Reads histogram bins from a CSV file with lower radius, upper radius and count on every line.
+/// Lines that do not start with a number, like a header, are skipped.
+fn load_histogram(filename: &str) -> Result<RadiusDistribution, Error> {
+    let contents = fs::read_to_string(filename)?;
+    let mut bins = Vec::new();
+    let mut weights = Vec::new();
+
+    for (line_index, line) in contents.lines().enumerate() {
+        let values: Vec<&str> = line.split(',').map(|v| v.trim()).collect();
+        if values[0].parse::<f32>().is_err() {
+            continue;
+        }
+        let parsed: Vec<f32> = values.iter().filter_map(|v| v.parse().ok()).collect();
+        if parsed.len() != 3 || parsed[0] > parsed[1] || parsed[2] < 0.0 {
+            return Err(Error::new(ErrorKind::InvalidData,
+                format!("Histogram {} line {}: expected lower radius, upper radius and count", filename, line_index + 1)));
+        }
+        bins.push((parsed[0], parsed[1]));
+        weights.push(parsed[2]);
+    }
+
+    if weights.iter().sum::<f32>() <= 0.0 {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Histogram {} has no counts", filename)));
+    }
+    return Ok(RadiusDistribution::Empirical { bins, weights });
+}
+
+impl RadiusDistribution {
+    pub fn new(gen_data: &GeneratorData) -> Result<Self, Error> {
+        let p = &gen_data.radius_parameters;
+        let (min, max) = (gen_data.min_sphere_radius, gen_data.max_sphere_radius);
+        let needs = |count: usize| {
+            if p.len() < count {
+                return Err(parameter_error(format!("Radius distribution {} needs {} parameters", gen_data.radius_distribution, count)));
+            }
+            return Ok(());
+        };
+
+        match gen_data.radius_distribution.as_str() {
+            "normal" => {
+                needs(2)?;
+                return Ok(RadiusDistribution::Normal { mean: p[0], deviation: p[1], min, max });
+            },
+            "lognormal" => {
+                needs(2)?;
+                return Ok(RadiusDistribution::LogNormal { median: p[0], sigma: p[1], min, max });
+            },
+            "gamma" => {
+                needs(2)?;
+                return Ok(RadiusDistribution::Gamma { shape: p[0], scale: p[1], min, max });
+            },
+            "powerlaw" => {
+                needs(1)?;
+                return Ok(RadiusDistribution::PowerLaw { exponent: p[0], min, max });
+            },
+            "discrete" => {
+                needs(2)?;
+                if !p.len().is_multiple_of(2) {
+                    return Err(parameter_error(String::from("Discrete radius distribution needs pairs of radius and weight")));
+                }
+                let radii = p.iter().step_by(2).copied().collect();
+                let weights = p.iter().skip(1).step_by(2).copied().collect();
+                return Ok(RadiusDistribution::Discrete { radii, weights });
+            },
+            "empirical" => {
+                let filename = match &gen_data.radius_histogram {
+                    Some(f) => f,
+                    None => {
+                        return Err(parameter_error(String::from("Empirical radius distribution needs a histogram file")));
+                    }
+                };
+                return load_histogram(filename);
+            },
+            _ => {
+                return Ok(RadiusDistribution::Uniform { min, max });
+            }
+        }
+    }
+
+    /// Draws from an unbounded distribution until the sample is in the radius range.
+    fn truncated<F: Fn() -> f32>(draw: F, min: f32, max: f32) -> f32 {
+        for _ in 0..MAX_TRIES {
+            let r = draw();
+            if r >= min && r <= max {
+                return r;
+            }
+        }
+        return draw().clamp(min, max);
+    }
+
+    pub fn sample(&self) -> f32 {
+        let mut rand = rand::thread_rng();
+        match self {
+            RadiusDistribution::Uniform { min, max } => {
+                return rand.gen::<f32>() * (max - min) + min;
+            },
+            RadiusDistribution::Normal { mean, deviation, min, max } => {
+                return Self::truncated(|| mean + deviation * normal_sample(&mut rand::thread_rng()), *min, *max);
+            },
+            RadiusDistribution::LogNormal { median, sigma, min, max } => {
+                return Self::truncated(|| median * (sigma * normal_sample(&mut rand::thread_rng())).exp(), *min, *max);
+            },
+            RadiusDistribution::Gamma { shape, scale, min, max } => {
+                return Self::truncated(|| scale * gamma_sample(*shape), *min, *max);
+            },
+            RadiusDistribution::PowerLaw { exponent, min, max } => {
+                // Inverse transform of p(r) ~ r^-exponent on the radius range
+                let u = rand.gen::<f32>();
+                let min = min.max(f32::EPSILON);
+                if (exponent - 1.0).abs() < 1e-6 {
+                    return min * (max / min).powf(u);
+                }
+                let k = 1.0 - exponent;
+                return (min.powf(k) + u * (max.powf(k) - min.powf(k))).powf(1.0 / k);
+            },
+            RadiusDistribution::Discrete { radii, weights } => {
+                return radii[weighted_index(weights)];
+            },
+            RadiusDistribution::Empirical { bins, weights } => {
+                let (low, high) = bins[weighted_index(weights)];
+                return low + rand.gen::<f32>() * (high - low);
+            }
+        }
+    }
+
+    /// Largest radius the distribution can give, used to size neighbour grids.
+    pub fn max_radius(&self) -> f32 {
+        match self {
+            RadiusDistribution::Uniform { max, .. } | RadiusDistribution::Normal { max, .. } | RadiusDistribution::LogNormal { max, .. }
+                | RadiusDistribution::Gamma { max, .. } | RadiusDistribution::PowerLaw { max, .. } => *max,
+            RadiusDistribution::Discrete { radii, .. } => radii.iter().fold(0.0, |m, r| m.max(*r)),
+            RadiusDistribution::Empirical { bins, .. } => bins.iter().fold(0.0, |m, b| m.max(b.1))
+        }
+    }
+
+    /// Parameters of the distribution for the execution data.
+    pub fn describe(gen_data: &GeneratorData) -> String {
+        let p = &gen_data.radius_parameters;
+        let range = format!("truncated to ({}, {})", gen_data.min_sphere_radius, gen_data.max_sphere_radius);
+        match gen_data.radius_distribution.as_str() {
+            "normal" => format!("normal (mean {}, standard deviation {}), {}", p[0], p[1], range),
+            "lognormal" => format!("lognormal (median {}, sigma {}), {}", p[0], p[1], range),
+            "gamma" => format!("gamma (shape {}, scale {}), {}", p[0], p[1], range),
+            "powerlaw" => format!("powerlaw (exponent {}) on ({}, {})", p[0], gen_data.min_sphere_radius, gen_data.max_sphere_radius),
+            "discrete" => {
+                let pairs: Vec<String> = p.chunks(2).map(|c| format!("{}:{}", c[0], c.get(1).unwrap_or(&0.0))).collect();
+                format!("discrete (radius:weight {})", pairs.join(", "))
+            },
+            "empirical" => format!("empirical (histogram {})", gen_data.radius_histogram.clone().unwrap_or_default()),
+            _ => format!("uniform ({}, {})", gen_data.min_sphere_radius, gen_data.max_sphere_radius)
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use crate::GeneratorData;
+
+pub const RANGE_POLICIES: [&str; 3] = ["clamp", "reflect", "rescale"];
+
+/// Brings the densities into the output range. Clamping sets values outside to the nearest bound, reflecting
+/// mirrors them back at the bounds, and rescaling maps the span of the values linearly onto the range, so
+/// volumes already inside it are unchanged. Returns the number of voxels below and above the range before.
+pub fn apply_range(gen_data: &GeneratorData, densities: &mut Vec<f32>) -> (usize, usize) {
+    let (low, high) = gen_data.range;
+    let below = densities.iter().filter(|d| **d < low).count();
+    let above = densities.iter().filter(|d| **d > high).count();
+    if below + above == 0 {
+        return (0, 0);
+    }
+
+    match gen_data.range_policy.as_str() {
+        "reflect" => {
+            let width = high - low;
+            for d in densities.iter_mut() {
+                let t = (*d - low).rem_euclid(2.0 * width);
+                *d = low + if t > width { 2.0 * width - t } else { t };
+            }
+        },
+        "rescale" => {
+            let min = densities.iter().fold(low, |m, d| m.min(*d));
+            let max = densities.iter().fold(high, |m, d| m.max(*d));
+            let scale = (high - low) / (max - min);
+            for d in densities.iter_mut() {
+                *d = (low + (*d - min) * scale).clamp(low, high);
+            }
+        },
+        _ => {
+            for d in densities.iter_mut() {
+                *d = d.clamp(low, high);
+            }
+        }
+    }
+    return (below, above);
+}
+
+pub fn describe(gen_data: &GeneratorData) -> String {
+    return format!("({}, {}), {}", gen_data.range.0, gen_data.range.1, gen_data.range_policy);
+}
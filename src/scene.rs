@@ -0,0 +1,332 @@
+//! Scene files describe instances as CSG trees written as s-expressions,
+//! with all positions and sizes given in scene units, relative to the `--extent`
+//! of the volume (the largest axis has length 1 by default):
+//!
+//! ```text
+//! # A sphere with a cylindrical hole
+//! (object 120
+//!     (difference
+//!         (sphere 0.5 0.5 0.5 0.3)
+//!         (cylinder 0.5 0.5 0.5 0.1 0.8 90 0 0)))
+//! ```
+//!
+//! Every `object` is labelled as one instance, with ids assigned in file order.
+//! Thin sheets are written as `(thin density thickness shape)` and are usually
+//! built from `(surface shape)`, the boundary of a shape.
+
+use std::{fs, io::{Error, ErrorKind}};
+
+use crate::{csg::{CsgNode, Primitive, Transform, MAX_STACK_DEPTH}, instance::Instance, vector3float::Vector3Float};
+
+enum Token {
+    Open,
+    Close,
+    Word(String)
+}
+
+struct Tokens {
+    tokens: Vec<(Token, usize)>,
+    position: usize
+}
+
+fn tokenize(contents: &str) -> Tokens {
+    let mut tokens = Vec::new();
+
+    for (line_index, line) in contents.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(c) => &line[..c],
+            None => line
+        };
+        let spaced = line.replace('(', " ( ").replace(')', " ) ");
+        for word in spaced.split_whitespace() {
+            let token = match word {
+                "(" => Token::Open,
+                ")" => Token::Close,
+                w => Token::Word(w.to_string())
+            };
+            tokens.push((token, line_index + 1));
+        }
+    }
+
+    return Tokens {
+        tokens,
+        position: 0
+    };
+}
+
+fn parse_error(line: usize, message: &str) -> Error {
+    return Error::new(ErrorKind::InvalidData, format!("Scene line {}: {}", line, message));
+}
+
+impl Tokens {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.position).or(self.tokens.last()) {
+            Some((_, l)) => *l,
+            None => 0
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.position >= self.tokens.len();
+    }
+
+    fn peek_close(&self) -> bool {
+        return matches!(self.tokens.get(self.position), Some((Token::Close, _)));
+    }
+
+    fn expect_open(&mut self) -> Result<(), Error> {
+        match self.tokens.get(self.position) {
+            Some((Token::Open, _)) => {
+                self.position += 1;
+                return Ok(());
+            },
+            _ => {
+                return Err(parse_error(self.line(), "expected '('"));
+            }
+        }
+    }
+
+    fn expect_close(&mut self) -> Result<(), Error> {
+        match self.tokens.get(self.position) {
+            Some((Token::Close, _)) => {
+                self.position += 1;
+                return Ok(());
+            },
+            _ => {
+                return Err(parse_error(self.line(), "expected ')'"));
+            }
+        }
+    }
+
+    fn word(&mut self) -> Result<String, Error> {
+        match self.tokens.get(self.position) {
+            Some((Token::Word(w), _)) => {
+                self.position += 1;
+                return Ok(w.clone());
+            },
+            _ => {
+                return Err(parse_error(self.line(), "expected a value"));
+            }
+        }
+    }
+
+    fn float(&mut self) -> Result<f32, Error> {
+        let line = self.line();
+        let word = self.word()?;
+        match word.parse() {
+            Ok(f) => {
+                return Ok(f);
+            },
+            Err(_) => {
+                return Err(parse_error(line, &format!("'{}' is not a valid float", word)));
+            }
+        }
+    }
+
+    fn floats(&mut self, count: usize) -> Result<Vec<f32>, Error> {
+        let mut values = Vec::with_capacity(count);
+        for _ in 0..count {
+            values.push(self.float()?);
+        }
+        return Ok(values);
+    }
+
+    /// Reads optional rotation angles placed after the primitive parameters.
+    fn transform(&mut self, origin: Vector3Float) -> Result<Transform, Error> {
+        if self.peek_close() {
+            return Ok(Transform::new(origin));
+        }
+        let a = self.floats(3)?;
+        return Ok(Transform::with_euler_angles(origin, &Vector3Float::new(a[0], a[1], a[2])));
+    }
+}
+
+fn parse_node(tokens: &mut Tokens) -> Result<CsgNode, Error> {
+    tokens.expect_open()?;
+    let line = tokens.line();
+    let name = tokens.word()?;
+
+    let node = match name.as_str() {
+        "union" | "intersection" | "difference" => {
+            let mut children = Vec::new();
+            while !tokens.peek_close() {
+                if tokens.is_empty() {
+                    return Err(parse_error(line, &format!("unterminated '{}'", name)));
+                }
+                children.push(parse_node(tokens)?);
+            }
+            if children.len() < 2 {
+                return Err(parse_error(line, &format!("'{}' needs at least two operands", name)));
+            }
+            match name.as_str() {
+                "union" => CsgNode::Union(children),
+                "intersection" => CsgNode::Intersection(children),
+                _ => CsgNode::Difference(children)
+            }
+        },
+        "surface" => {
+            CsgNode::Surface(Box::new(parse_node(tokens)?))
+        },
+        "sphere" => {
+            let v = tokens.floats(4)?;
+            let origin = Vector3Float::new(v[0], v[1], v[2]);
+            CsgNode::Primitive(Primitive::Sphere { radius: v[3] }, Transform::new(origin))
+        },
+        "ellipsoid" => {
+            let v = tokens.floats(6)?;
+            let origin = Vector3Float::new(v[0], v[1], v[2]);
+            let radii = Vector3Float::new(v[3], v[4], v[5]);
+            CsgNode::Primitive(Primitive::Ellipsoid { radii }, tokens.transform(origin)?)
+        },
+        "cylinder" => {
+            let v = tokens.floats(5)?;
+            let origin = Vector3Float::new(v[0], v[1], v[2]);
+            let primitive = Primitive::Cylinder { radius: v[3], half_height: v[4] / 2.0 };
+            CsgNode::Primitive(primitive, tokens.transform(origin)?)
+        },
+        "box" => {
+            let v = tokens.floats(6)?;
+            let origin = Vector3Float::new(v[0], v[1], v[2]);
+            let half_extents = Vector3Float::new(v[3] / 2.0, v[4] / 2.0, v[5] / 2.0);
+            CsgNode::Primitive(Primitive::Cuboid { half_extents }, tokens.transform(origin)?)
+        },
+        _ => {
+            return Err(parse_error(line, &format!("unknown shape '{}'", name)));
+        }
+    };
+
+    tokens.expect_close()?;
+    return Ok(node);
+}
+
+fn parse_object(tokens: &mut Tokens, id: u32, voxel_size: f32) -> Result<Instance, Error> {
+    tokens.expect_open()?;
+    let line = tokens.line();
+    let name = tokens.word()?;
+    if name != "object" && name != "thin" {
+        return Err(parse_error(line, &format!("expected 'object' or 'thin', found '{}'", name)));
+    }
+
+    let density_word = tokens.word()?;
+    let density: u32 = match density_word.parse() {
+        Ok(d) => d,
+        Err(_) => {
+            return Err(parse_error(line, &format!("'{}' is not a valid density", density_word)));
+        }
+    };
+    let thickness = if name == "thin" {
+        tokens.float()?
+    } else {
+        0.0
+    };
+
+    let shape = parse_node(tokens)?;
+    tokens.expect_close()?;
+
+    if shape.stack_depth() > MAX_STACK_DEPTH {
+        return Err(parse_error(line, &format!("object is nested deeper than {} levels", MAX_STACK_DEPTH)));
+    }
+
+    if thickness > 0.0 {
+        return Ok(Instance::new_thin(id, density, shape, thickness, voxel_size));
+    }
+    return Ok(Instance::new(id, density, shape));
+}
+
+/// Parses a single shape written in the scene file syntax, like the shapes in instance data.
+pub fn parse_shape(text: &str) -> Result<CsgNode, Error> {
+    let mut tokens = tokenize(text);
+    let shape = parse_node(&mut tokens)?;
+    if !tokens.is_empty() {
+        return Err(parse_error(tokens.line(), "unexpected text after the shape"));
+    }
+    if shape.stack_depth() > MAX_STACK_DEPTH {
+        return Err(parse_error(1, &format!("shape is nested deeper than {} levels", MAX_STACK_DEPTH)));
+    }
+    return Ok(shape);
+}
+
+/// Loads instances from a scene file, the voxel size is needed for the bounds of thin objects.
+pub fn load_scene(filename: &str, voxel_size: f32) -> Result<Vec<Instance>, Error> {
+    let contents = fs::read_to_string(filename)?;
+    let mut tokens = tokenize(&contents);
+
+    let mut instances = Vec::new();
+    while !tokens.is_empty() {
+        let id = instances.len() as u32 + 1;
+        instances.push(parse_object(&mut tokens, id, voxel_size)?);
+    }
+
+    println!("Loaded {} objects from scene {}", instances.len(), filename);
+    return Ok(instances);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shape(text: &str) -> CsgNode {
+        match parse_shape(text) {
+            Ok(s) => s,
+            Err(e) => panic!("'{}' did not parse: {}", text, e)
+        }
+    }
+
+    fn inside(node: &CsgNode, x: f32, y: f32, z: f32) -> bool {
+        return node.signed_distance(&Vector3Float::new(x, y, z)) <= 0.0;
+    }
+
+    #[test]
+    fn primitives_have_their_size() {
+        let sphere = shape("(sphere 0.5 0.5 0.5 0.25)");
+        assert!((sphere.signed_distance(&Vector3Float::new(0.5, 0.5, 0.5)) + 0.25).abs() < 1e-6);
+        assert!((sphere.signed_distance(&Vector3Float::new(1.0, 0.5, 0.5)) - 0.25).abs() < 1e-6);
+
+        // Boxes and cylinders are given by full sizes, not half sizes
+        let cuboid = shape("(box 0.5 0.5 0.5 0.4 0.2 0.1)");
+        assert!(inside(&cuboid, 0.69, 0.5, 0.5) && !inside(&cuboid, 0.71, 0.5, 0.5));
+        assert!(inside(&cuboid, 0.5, 0.59, 0.5) && !inside(&cuboid, 0.5, 0.61, 0.5));
+        let cylinder = shape("(cylinder 0.5 0.5 0.5 0.1 0.4)");
+        assert!(inside(&cylinder, 0.5, 0.5, 0.69) && !inside(&cylinder, 0.5, 0.5, 0.71));
+        assert!(inside(&cylinder, 0.59, 0.5, 0.5) && !inside(&cylinder, 0.61, 0.5, 0.5));
+    }
+
+    #[test]
+    fn trailing_angles_rotate_primitives() {
+        // A long thin box turned by 90 degrees around z lies along y
+        let rotated = shape("(box 0.5 0.5 0.5 0.6 0.1 0.1 0 0 90)");
+        assert!(inside(&rotated, 0.5, 0.75, 0.5));
+        assert!(!inside(&rotated, 0.75, 0.5, 0.5));
+    }
+
+    #[test]
+    fn operations_combine_shapes() {
+        let hollow = shape("(difference (sphere 0.5 0.5 0.5 0.3) (sphere 0.5 0.5 0.5 0.1))");
+        assert!(!inside(&hollow, 0.5, 0.5, 0.5));
+        assert!(inside(&hollow, 0.7, 0.5, 0.5));
+        let both = shape("(union (sphere 0.2 0.5 0.5 0.1) (sphere 0.8 0.5 0.5 0.1))");
+        assert!(inside(&both, 0.2, 0.5, 0.5) && inside(&both, 0.8, 0.5, 0.5) && !inside(&both, 0.5, 0.5, 0.5));
+        let lens = shape("(intersection (sphere 0.4 0.5 0.5 0.2) (sphere 0.6 0.5 0.5 0.2))");
+        assert!(inside(&lens, 0.5, 0.5, 0.5) && !inside(&lens, 0.25, 0.5, 0.5));
+    }
+
+    #[test]
+    fn shapes_survive_writing_and_parsing() {
+        let text = "(difference (box 0.5 0.5 0.5 0.4 0.3 0.2 10 20 30) (union (ellipsoid 0.5 0.5 0.5 0.1 0.05 0.2 0 45 0) (cylinder 0.6 0.5 0.5 0.05 0.5)))";
+        let original = shape(text);
+        let parsed = shape(&original.to_scene_string());
+        for i in 0..10 {
+            let p = Vector3Float::new(0.3 + 0.04 * i as f32, 0.45 + 0.01 * i as f32, 0.6 - 0.02 * i as f32);
+            assert!((original.signed_distance(&p) - parsed.signed_distance(&p)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn invalid_shapes_are_rejected() {
+        for text in ["", "sphere 0.5 0.5 0.5 0.1", "(sphere 0.5 0.5 0.5)", "(sphere 0.5 0.5 0.5 a)", "(cone 0.5 0.5 0.5 0.1)",
+            "(union (sphere 0.5 0.5 0.5 0.1))", "(union (sphere 0.5 0.5 0.5 0.1)", "(sphere 0.5 0.5 0.5 0.1) (sphere 0.5 0.5 0.5 0.1)",
+            "(box 0.5 0.5 0.5 0.1 0.1 0.1 90)"] {
+            assert!(parse_shape(text).is_err(), "'{}' should not parse", text);
+        }
+    }
+}
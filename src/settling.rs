@@ -0,0 +1,171 @@
+use rand::Rng;
+
+use crate::{GeneratorData, packing::Grid, sphere::Sphere, vector3float::Vector3Float, container::Container, radius::RadiusDistribution};
+
+/// Fall distance of one settling step, as a fraction of the sphere radius.
+const STEP: f32 = 0.1;
+const MAX_STEPS: usize = 5000;
+const PROJECTION_ITERATIONS: usize = 4;
+/// A sphere is at rest when it moved less than this fraction of its radius over the last steps.
+const REST_DISTANCE: f32 = 0.01;
+const REST_STEPS: usize = 20;
+
+/// Extent of the volume the bed settles in, and whether its side walls wrap around.
+struct Bounds {
+    extent: [f32; 3],
+    periodic: bool
+}
+
+/// Vector from b to a, wrapped in x and y when the volume is periodic. Gravity acts along -z,
+/// so the floor and the top are never wrapped.
+fn difference(a: &[f32; 3], b: &[f32; 3], bounds: &Bounds) -> [f32; 3] {
+    let mut d = [a[0] - b[0], a[1] - b[1], a[2] - b[2]];
+    if bounds.periodic {
+        for (component, size) in d.iter_mut().zip(bounds.extent.iter()).take(2) {
+            *component -= size * (*component / size).round();
+        }
+    }
+    return d;
+}
+
+/// Keeps the sphere above the floor and between the side walls, or wraps it around them,
+/// and inside the container if there is one.
+fn constrain(p: &mut [f32; 3], r: f32, bounds: &Bounds, container: Option<&Container>) {
+    for (coordinate, size) in p.iter_mut().zip(bounds.extent.iter()).take(2) {
+        *coordinate = if bounds.periodic {
+            coordinate.rem_euclid(*size)
+        } else {
+            coordinate.clamp(r.min(size / 2.0), size - r.min(size / 2.0))
+        };
+    }
+    p[2] = p[2].max(r);
+    if let Some(container) = container {
+        container.constrain(p, r);
+    }
+}
+
+/// Height at which a sphere falling straight down first touches the floor or the bed.
+fn first_contact(p: &[f32; 3], r: f32, positions: &Vec<[f32; 3]>, radii: &Vec<f32>, bounds: &Bounds) -> f32 {
+    let mut height = r;
+    for (q, rq) in positions.iter().zip(radii.iter()) {
+        let d = difference(p, q, bounds);
+        let horizontal = d[0]*d[0] + d[1]*d[1];
+        let reach = (r + rq) * (r + rq);
+        if horizontal < reach {
+            height = height.max(q[2] + (reach - horizontal).sqrt());
+        }
+    }
+    return height;
+}
+
+/// Pushes the sphere out of the spheres it overlaps, returns the largest overlap found.
+fn project(p: &mut [f32; 3], r: f32, positions: &Vec<[f32; 3]>, radii: &Vec<f32>, grid: &Grid, bounds: &Bounds, container: Option<&Container>) -> f32 {
+    let mut worst = 0.0f32;
+    for n in grid.neighbours(p, bounds.periodic) {
+        let d = difference(p, &positions[n], bounds);
+        let distance = (d[0]*d[0] + d[1]*d[1] + d[2]*d[2]).sqrt();
+        let overlap = r + radii[n] - distance;
+        if overlap > 0.0 && distance > 0.0 {
+            worst = worst.max(overlap);
+            for axis in 0..3 {
+                p[axis] += d[axis] / distance * overlap;
+            }
+        }
+    }
+    constrain(p, r, bounds, container);
+    return worst;
+}
+
+/// Moves a falling sphere down step by step, pushing it out of the spheres it overlaps,
+/// so it rolls off the bed until it rests in a pocket or on the floor. Returns false when the
+/// sphere got stuck in a gap too small for it.
+fn settle(p: &mut [f32; 3], r: f32, positions: &Vec<[f32; 3]>, radii: &Vec<f32>, grid: &Grid, bounds: &Bounds, container: Option<&Container>) -> bool {
+    let mut moved = Vec::with_capacity(MAX_STEPS);
+    for _ in 0..MAX_STEPS {
+        let start = *p;
+        p[2] -= STEP * r;
+
+        for _ in 0..PROJECTION_ITERATIONS {
+            project(p, r, positions, radii, grid, bounds, container);
+        }
+
+        let d = difference(p, &start, bounds);
+        moved.push((d[0]*d[0] + d[1]*d[1] + d[2]*d[2]).sqrt());
+        if moved.len() >= REST_STEPS && moved[moved.len() - REST_STEPS..].iter().sum::<f32>() < REST_DISTANCE * r {
+            break;
+        }
+    }
+
+    // Without gravity, the remaining overlap between the contacts is resolved
+    for _ in 0..MAX_STEPS {
+        project(p, r, positions, radii, grid, bounds, container);
+        let mut check = *p;
+        if project(&mut check, r, positions, radii, grid, bounds, container) < REST_DISTANCE * r {
+            return true;
+        }
+    }
+    return false;
+}
+
+/// Sequential deposition: spheres are dropped one by one at random positions above the bed and
+/// settle under gravity along -z, until the count is reached or the bed reaches the top of the volume.
+/// Spheres that cannot settle without overlapping in the given attempts are left out and counted in the report.
+pub fn settle_spheres(gen_data: &GeneratorData, radii_distribution: &RadiusDistribution, densities: &Vec<u32>, report: &mut Vec<String>) -> Vec<Sphere> {
+    let mut rand = rand::thread_rng();
+    let e = &gen_data.extent;
+    let bounds = Bounds {
+        extent: [e.x, e.y, e.z],
+        periodic: gen_data.boundary == "periodic"
+    };
+    let container = Container::new(gen_data);
+    let mut grid = Grid::new(2.0 * radii_distribution.max_radius(), &bounds.extent);
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut radii: Vec<f32> = Vec::new();
+    let mut unsettled = 0;
+
+    for _ in 0..gen_data.sphere_count {
+        let r = radii_distribution.sample();
+        let mut p = [0.0; 3];
+        let mut settled = false;
+        for _ in 0..gen_data.placement_attempts {
+            p = [rand.gen::<f32>() * e.x, rand.gen::<f32>() * e.y, 0.0];
+            constrain(&mut p, r, &bounds, container.as_ref());
+            p[2] = first_contact(&p, r, &positions, &radii, &bounds);
+            if settle(&mut p, r, &positions, &radii, &grid, &bounds, container.as_ref()) {
+                settled = true;
+                break;
+            }
+        }
+        // Spheres that only rest on others by overlapping them are left out of the bed
+        if !settled {
+            unsettled += 1;
+            continue;
+        }
+
+        if p[2] + r > e.z {
+            println!("Warning: Settled bed reached the top of the volume after {} spheres", positions.len());
+            report.push(format!("Settled bed reached the top of the volume after {} spheres", positions.len()));
+            break;
+        }
+        grid.insert(positions.len(), &p);
+        positions.push(p);
+        radii.push(r);
+    }
+
+    if unsettled > 0 {
+        println!("Warning: {} spheres could not settle without overlapping and were left out", unsettled);
+        report.push(format!("Spheres left out for not settling without overlap: {}", unsettled));
+    }
+
+    let mut spheres = Vec::with_capacity(positions.len());
+    for (index, (p, r)) in positions.iter().zip(radii.iter()).enumerate() {
+        let density = densities[index % densities.len()];
+        spheres.push(Sphere::new(index as u32 + 1, *r, Vector3Float::new(p[0], p[1], p[2]), density));
+    }
+    spheres.sort();
+
+    let height = positions.iter().zip(radii.iter()).map(|(p, r)| p[2] + r).fold(0.0, f32::max);
+    report.push(format!("Settled bed height: {}", height));
+    println!("Settled {} spheres", spheres.len());
+    return spheres;
+}
@@ -1,8 +1,6 @@
 use std::cmp::Ordering;
 
-use rand::Rng;
-
-use crate::vector3float::Vector3Float;
+use crate::{vector3float::Vector3Float, instance::Instance, csg::{CsgNode, Primitive, Transform}, placement::PointProcess, lattice::LatticeSite, radius::RadiusDistribution, profile::Profile};
 
 #[derive(Clone)]
 pub struct Sphere {
@@ -10,6 +8,8 @@ pub struct Sphere {
     radius: f32,
     origin: Vector3Float,
     density: u32,
+    lattice_site: Option<LatticeSite>,
+    profile: Option<Profile>,
     pub bounding_box: ((f32, f32), (f32, f32), (f32, f32))
 }
 
@@ -30,25 +30,77 @@ impl Sphere {
         return &self.origin;
     }
 
-    pub fn generate_sphere(min_rad: f32, max_rad: f32, density: u32, id: u32) -> Self {
-        let mut rand = rand::thread_rng();
+    pub fn lattice_site(&self) -> Option<&LatticeSite> {
+        return self.lattice_site.as_ref();
+    }
 
-        let o = Vector3Float::new(rand.gen(), rand.gen(), rand.gen());
-        let r = rand.gen::<f32>() * (max_rad - min_rad) + min_rad;
+    pub fn profile(&self) -> Option<&Profile> {
+        return self.profile.as_ref();
+    }
 
-        let x_range = (o.x - r, o.x + r);
-        let y_range = (o.y - r, o.y + r);
-        let z_range = (o.z - r, o.z + r);
+    pub fn new(id: u32, radius: f32, origin: Vector3Float, density: u32) -> Self {
+        let x_range = (origin.x - radius, origin.x + radius);
+        let y_range = (origin.y - radius, origin.y + radius);
+        let z_range = (origin.z - radius, origin.z + radius);
 
         return Self {
             id,
-            radius: r,
-            origin: o,
+            radius,
+            origin,
             density,
+            lattice_site: None,
+            profile: None,
             bounding_box: (x_range, y_range, z_range)
         }
     }
 
+    pub fn with_lattice_site(mut self, site: LatticeSite) -> Self {
+        self.lattice_site = Some(site);
+        return self;
+    }
+
+    pub fn with_profile(mut self, profile: Option<Profile>) -> Self {
+        self.profile = profile;
+        return self;
+    }
+
+    /// Samples a sphere with its radius drawn from the distribution and its origin from the point process. When the sphere has
+    /// to be fully inside the volume, the origin is sampled so the bounding box fits.
+    pub fn generate_sphere(radii: &RadiusDistribution, density: u32, id: u32, process: &PointProcess, placed: &[Sphere], inside: bool) -> Self {
+        let r = radii.sample();
+        let o = process.sample(placed, r, inside);
+
+        return Self::new(id, r, o, density);
+    }
+
+    pub fn is_inside_volume(&self, extent: &Vector3Float) -> bool {
+        let bb = &self.bounding_box;
+        return bb.0.0 >= 0.0 && bb.1.0 >= 0.0 && bb.2.0 >= 0.0 && bb.0.1 <= extent.x && bb.1.1 <= extent.y && bb.2.1 <= extent.z;
+    }
+
+    /// Whether the two spheres are closer than the given gap between their surfaces.
+    /// In a periodic volume, given by its extent, the distance to the nearest periodic image is used.
+    pub fn overlaps(&self, other: &Sphere, gap: f32, periodic: Option<&Vector3Float>) -> bool {
+        let min_distance = self.radius + other.radius + gap;
+        let mut a = self.origin.x - other.origin.x;
+        let mut b = self.origin.y - other.origin.y;
+        let mut c = self.origin.z - other.origin.z;
+        if let Some(e) = periodic {
+            a -= e.x * (a / e.x).round();
+            b -= e.y * (b / e.y).round();
+            c -= e.z * (c / e.z).round();
+        }
+        return a*a + b*b + c*c < min_distance * min_distance;
+    }
+
+    pub fn to_instance(&self) -> Instance {
+        let shape = CsgNode::Primitive(
+            Primitive::Sphere { radius: self.radius },
+            Transform::new(self.origin.clone())
+        );
+        return Instance::new(self.id, self.density, shape).with_class("sphere").with_profile(self.profile.clone());
+    }
+
     /*pub fn is_point_in_sphere(&self, p: &Vector3Float) -> bool {
         //println!("{}", Vector3Float::distance(p, &self.origin));
         if Vector3Float::distance(p, &self.origin) <= self.radius {
@@ -0,0 +1,164 @@
+use crate::vector3float::Vector3Float;
+
+pub const TEXTURE_NAMES: [&str; 2] = ["perlin", "simplex"];
+
+/// Codes of the textures in the texture buffer, matches the TEXTURE_ constants in kernel.cl.
+const TEXTURE_NONE: f32 = 0.0;
+const TEXTURE_PERLIN: f32 = 1.0;
+const TEXTURE_SIMPLEX: f32 = 2.0;
+
+/// Floats per texture in the texture buffer: code, scale, octaves, amplitude.
+pub const TEXTURE_STRIDE: usize = 4;
+
+/// Largest number of octaves, finer ones are below the voxel size for any practical scale.
+pub const MAX_OCTAVES: u32 = 8;
+
+/// Procedural gradient noise added to the density. The scale is the size of the coarsest features
+/// in scene units, every further octave halves it and its weight (fractal Brownian motion).
+/// The amplitude is the largest density change.
+#[derive(Clone)]
+pub struct Texture {
+    pub kind: String,
+    pub scale: f32,
+    pub octaves: u32,
+    pub amplitude: f32
+}
+
+/// Integer hash of a lattice point, the same in kernel.cl so both backends give the same texture.
+fn hash(x: i32, y: i32, z: i32, seed: u32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(73856093) ^ (y as u32).wrapping_mul(19349663) ^ (z as u32).wrapping_mul(83492791);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846ca68b);
+    h ^= h >> 16;
+    return h;
+}
+
+/// Dot product with one of the 12 edge directions of a cube, picked by the hash.
+fn gradient(h: u32, x: f32, y: f32, z: f32) -> f32 {
+    let k = h & 15;
+    let u = if k < 8 { x } else { y };
+    let v = if k < 4 { y } else if k == 12 || k == 14 { x } else { z };
+    return (if k & 1 != 0 { -u } else { u }) + (if k & 2 != 0 { -v } else { v });
+}
+
+fn fade(t: f32) -> f32 {
+    return t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    return a + t * (b - a);
+}
+
+fn perlin(p: &Vector3Float, seed: u32) -> f32 {
+    let (fx, fy, fz) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let (x, y, z) = (fx as i32, fy as i32, fz as i32);
+    let (dx, dy, dz) = (p.x - fx, p.y - fy, p.z - fz);
+    let corner = |i: i32, j: i32, k: i32| gradient(hash(x + i, y + j, z + k, seed), dx - i as f32, dy - j as f32, dz - k as f32);
+    let (u, v, w) = (fade(dx), fade(dy), fade(dz));
+    return lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w
+    );
+}
+
+fn simplex(p: &Vector3Float, seed: u32) -> f32 {
+    const F3: f32 = 1.0 / 3.0;
+    const G3: f32 = 1.0 / 6.0;
+
+    // Skew into the simplex grid, find the cell and the order of the offsets to walk its corners
+    let s = (p.x + p.y + p.z) * F3;
+    let (i, j, k) = ((p.x + s).floor(), (p.y + s).floor(), (p.z + s).floor());
+    let t = (i + j + k) * G3;
+    let x0 = [p.x - (i - t), p.y - (j - t), p.z - (k - t)];
+    let (first, second) = if x0[0] >= x0[1] {
+        if x0[1] >= x0[2] { ([1, 0, 0], [1, 1, 0]) }
+        else if x0[0] >= x0[2] { ([1, 0, 0], [1, 0, 1]) }
+        else { ([0, 0, 1], [1, 0, 1]) }
+    } else if x0[1] < x0[2] { ([0, 0, 1], [0, 1, 1]) }
+    else if x0[0] < x0[2] { ([0, 1, 0], [0, 1, 1]) }
+    else { ([0, 1, 0], [1, 1, 0]) };
+
+    let (i, j, k) = (i as i32, j as i32, k as i32);
+    let mut sum = 0.0;
+    for (n, offset) in [[0, 0, 0], first, second, [1, 1, 1]].iter().enumerate() {
+        let c = n as f32 * G3;
+        let d = [x0[0] - offset[0] as f32 + c, x0[1] - offset[1] as f32 + c, x0[2] - offset[2] as f32 + c];
+        let falloff = 0.6 - d[0]*d[0] - d[1]*d[1] - d[2]*d[2];
+        if falloff > 0.0 {
+            let h = hash(i + offset[0], j + offset[1], k + offset[2], seed);
+            sum += falloff.powi(4) * gradient(h, d[0], d[1], d[2]);
+        }
+    }
+    return 32.0 * sum;
+}
+
+impl Texture {
+    pub fn code(&self) -> f32 {
+        match self.kind.as_str() {
+            "perlin" => TEXTURE_PERLIN,
+            "simplex" => TEXTURE_SIMPLEX,
+            _ => TEXTURE_NONE
+        }
+    }
+
+    /// Density change at the point. Matches texture_value in kernel.cl.
+    pub fn value(&self, p: &Vector3Float, seed: u32) -> f32 {
+        let mut sum = 0.0;
+        let mut weight = 1.0;
+        let mut total = 0.0;
+        let mut frequency = 1.0 / self.scale;
+        for octave in 0..self.octaves {
+            let q = Vector3Float::new(p.x * frequency, p.y * frequency, p.z * frequency);
+            let octave_seed = seed.wrapping_add(octave);
+            let n = if self.kind == "simplex" { simplex(&q, octave_seed) } else { perlin(&q, octave_seed) };
+            sum += weight * n;
+            total += weight;
+            weight *= 0.5;
+            frequency *= 2.0;
+        }
+        return self.amplitude * sum / total;
+    }
+
+    /// Texture from the arguments after its flag: name, scale, octaves and amplitude.
+    pub fn parse(args: &[String]) -> Option<Self> {
+        if args.len() < 4 || !TEXTURE_NAMES.contains(&args[0].as_str()) {
+            return None;
+        }
+        let scale = args[1].parse::<f32>().ok().filter(|s| *s > 0.0)?;
+        let octaves = args[2].parse::<u32>().ok().filter(|o| *o >= 1 && *o <= MAX_OCTAVES)?;
+        let amplitude = args[3].parse::<f32>().ok()?;
+        return Some(Self {
+            kind: args[0].clone(),
+            scale,
+            octaves,
+            amplitude
+        });
+    }
+
+    pub fn describe(texture: Option<&Texture>) -> String {
+        match texture {
+            Some(t) => format!("{}, scale {}, {} octaves, amplitude {}", t.kind, t.scale, t.octaves, t.amplitude),
+            None => String::from("none")
+        }
+    }
+}
+
+/// Seed of the texture of an instance, so neighbouring instances get different textures. The background uses id 0.
+pub fn instance_seed(seed: u32, id: u32) -> u32 {
+    return seed.wrapping_add(id.wrapping_mul(1013));
+}
+
+/// Instance and background textures for the kernel, unused ones have code 0.
+pub fn encode_textures(instance: Option<&Texture>, background: Option<&Texture>) -> Vec<f32> {
+    let mut encoded = Vec::with_capacity(2 * TEXTURE_STRIDE);
+    for texture in [instance, background] {
+        match texture {
+            Some(t) => encoded.extend_from_slice(&[t.code(), t.scale, t.octaves as f32, t.amplitude]),
+            None => encoded.extend_from_slice(&[TEXTURE_NONE; TEXTURE_STRIDE])
+        }
+    }
+    return encoded;
+}
@@ -0,0 +1,68 @@
+use std::io::Error;
+
+use rand::Rng;
+
+use crate::{GeneratorData, csg::{CsgNode, Primitive, Transform}, instance::Instance, vector3float::Vector3Float,
+    orientation::{OrientationDistribution, orientation_tensor, describe_tensor}};
+
+/// Random point in the volume.
+fn random_origin(extent: &Vector3Float) -> Vector3Float {
+    let mut rand = rand::thread_rng();
+    return Vector3Float::new(rand.gen::<f32>() * extent.x, rand.gen::<f32>() * extent.y, rand.gen::<f32>() * extent.z);
+}
+
+/// Flat disc, a cylinder without height gives the distance to the disc.
+fn random_disc(radius: f32, extent: &Vector3Float, rotation: [[f32; 3]; 3]) -> CsgNode {
+    let origin = random_origin(extent);
+    let primitive = Primitive::Cylinder { radius, half_height: 0.0 };
+    return CsgNode::Primitive(primitive, Transform::with_rotation(origin, rotation));
+}
+
+/// Spherical cap with the given extent and a random curvature radius of one to three extents.
+fn random_sheet(radius: f32, extent: &Vector3Float, rotation: [[f32; 3]; 3]) -> CsgNode {
+    let mut rand = rand::thread_rng();
+    let origin = random_origin(extent);
+    let transform = Transform::with_rotation(origin, rotation);
+
+    let curvature = radius * (1.0 + 2.0 * rand.gen::<f32>());
+    let center = transform.to_world(&Vector3Float::new(0.0, 0.0, -curvature));
+    let sphere = CsgNode::Primitive(Primitive::Sphere { radius: curvature }, Transform::new(center));
+
+    // The cylinder keeps the front of the sphere, its back is further than the cylinder height
+    let extent = CsgNode::Primitive(Primitive::Cylinder { radius, half_height: curvature }, transform);
+    return CsgNode::Intersection(vec![CsgNode::Surface(Box::new(sphere)), extent]);
+}
+
+/// Randomly placed discs and curved sheets, labelled with ids starting at first_id. Their normals
+/// follow the orientation distribution, and the realised orientation tensor is reported.
+pub fn generate_thin_instances(gen_data: &GeneratorData, first_id: u32, report: &mut Vec<String>) -> Result<Vec<Instance>, Error> {
+    let mut rand = rand::thread_rng();
+    let voxel_size = gen_data.voxel_size();
+    let orientations = OrientationDistribution::new(gen_data)?;
+    let mut instances = Vec::new();
+    let mut normals = Vec::new();
+
+    let count = gen_data.disc_count + gen_data.sheet_count;
+    for i in 0..count {
+        let radius = rand.gen::<f32>() * (gen_data.max_thin_radius - gen_data.min_thin_radius) + gen_data.min_thin_radius;
+        let thickness_voxels = rand.gen::<f32>() * (gen_data.max_thin_thickness - gen_data.min_thin_thickness) + gen_data.min_thin_thickness;
+        let density = match gen_data.thin_density {
+            Some(d) => d,
+            None => rand.gen_range(gen_data.empty_space+gen_data.noise_span..=gen_data.max_density() as u32)
+        };
+
+        let rotation = orientations.sample_rotation();
+        normals.push(Vector3Float::new(rotation[2][0], rotation[2][1], rotation[2][2]));
+        let (shape, class) = if i < gen_data.disc_count {
+            (random_disc(radius, &gen_data.extent, rotation), "disc")
+        } else {
+            (random_sheet(radius, &gen_data.extent, rotation), "sheet")
+        };
+        let instance = Instance::new_thin(first_id + i, density, shape, thickness_voxels * voxel_size, voxel_size);
+        instances.push(instance.with_class(class));
+    }
+
+    report.push(format!("Orientation tensor of thin feature normals: {}", describe_tensor(&orientation_tensor(&normals))));
+    println!("Generated {} discs and {} sheets", gen_data.disc_count, gen_data.sheet_count);
+    return Ok(instances);
+}
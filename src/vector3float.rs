@@ -18,4 +18,12 @@ impl Vector3Float {
         let c = v1.z - v2.z;
         return (a*a + b*b + c*c).sqrt();
     }*/
-}
\ No newline at end of file
+
+    pub fn sub(v1: &Vector3Float, v2: &Vector3Float) -> Vector3Float {
+        return Vector3Float::new(v1.x - v2.x, v1.y - v2.y, v1.z - v2.z);
+    }
+
+    pub fn length(&self) -> f32 {
+        return (self.x*self.x + self.y*self.y + self.z*self.z).sqrt();
+    }
+}